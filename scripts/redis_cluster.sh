@@ -0,0 +1,42 @@
+#!/usr/bin/env bash
+# start a local 3 master / 3 replica redis cluster on ports 7000-7005 for trying the
+# cluster paths of db_redis (slot splitting, node scripts, keyspace events).
+#   scripts/redis_cluster.sh start | stop
+# then point RedisPool at it:
+#   RedisPool::instance().set_url_list(vec!["redis://127.0.0.1:7000/".to_string(), ...]);
+# the cluster tests are ignored by default:
+#   cargo test --test redis_cluster -- --ignored
+set -e
+
+DIR="${REDIS_CLUSTER_DIR:-/tmp/rua_redis_cluster}"
+PORTS="7000 7001 7002 7003 7004 7005"
+
+start() {
+    mkdir -p "$DIR"
+    for port in $PORTS; do
+        mkdir -p "$DIR/$port"
+        redis-server --port "$port" --dir "$DIR/$port" --cluster-enabled yes \
+            --cluster-config-file nodes.conf --cluster-node-timeout 5000 \
+            --appendonly no --save "" --daemonize yes \
+            --notify-keyspace-events Ex --logfile "$DIR/$port/redis.log"
+    done
+    sleep 1
+    nodes=""
+    for port in $PORTS; do
+        nodes="$nodes 127.0.0.1:$port"
+    done
+    redis-cli --cluster create $nodes --cluster-replicas 1 --cluster-yes
+}
+
+stop() {
+    for port in $PORTS; do
+        redis-cli -p "$port" shutdown nosave 2>/dev/null || true
+    done
+    rm -rf "$DIR"
+}
+
+case "$1" in
+    start) start ;;
+    stop) stop ;;
+    *) echo "usage: $0 start|stop"; exit 1 ;;
+esac
@@ -0,0 +1,290 @@
+use crate::db_trait::{BatchResult, CharacterSet, DbTrait};
+use crate::db_redis::{RedisPool, RedisConnection, RedisPipeline};
+use crate::db_redis::codec;
+use rua_net_mgr::{NetMsg, NetResult};
+use rua_value_list::VarList;
+
+static CACHE_KEY_PREFIX: &'static str = "rua_db_cache";
+
+/// keywords whose next token names a table
+static TABLE_KEYWORDS: [&'static str; 5] = ["FROM", "JOIN", "INTO", "UPDATE", "TABLE"];
+
+/// cache-aside layer in front of a `DbTrait` backend.
+/// `select` results are stored in redis under the sql text (which carries the query parameters),
+/// and every stored key is tagged with the tables the query reads so `execute` / `insert`
+/// on those tables drop it again. redis failures only skip the cache, they never fail the query
+pub struct DbCache<T: DbTrait> {
+    pub db: T,
+    pub ttl: usize,
+    pub prefix: String,
+    pub hits: u64,
+    pub misses: u64,
+    pub in_transaction: bool,
+    pub pending_tables: Vec<String>,
+}
+
+impl<T: DbTrait> DbCache<T> {
+    pub fn new(db: T, ttl: usize) -> DbCache<T> {
+        DbCache {
+            db,
+            ttl,
+            prefix: CACHE_KEY_PREFIX.to_string(),
+            hits: 0,
+            misses: 0,
+            in_transaction: false,
+            pending_tables: vec![],
+        }
+    }
+
+    pub fn cache_key(&self, sql_cmd: &str) -> String {
+        format!("{}:q:{}", self.prefix, sql_cmd.trim())
+    }
+
+    pub fn tag_key(&self, table: &str) -> String {
+        format!("{}:tag:{}", self.prefix, table.to_lowercase())
+    }
+
+    /// like `select` but tagged with `tables` instead of the tables parsed from the sql.
+    /// inside a transaction the cache is skipped, other processes must not see uncommitted rows.
+    /// no redis connection is held while the backend runs the query, slow queries would drain the pool
+    pub fn select_tagged(&mut self, sql_cmd: &str, tables: &[String], msg: &mut NetMsg) -> NetResult<i32> {
+        if self.in_transaction {
+            return self.db.select(sql_cmd, msg);
+        }
+        let key = self.cache_key(sql_cmd);
+        let pool = RedisPool::instance();
+
+        if let Some(mut conn) = pool.get_redis_connection() {
+            let data: Option<Vec<u8>> = redis::cmd("GET").arg(&*key).query(&mut conn.conn).unwrap_or(None);
+            pool.release_redis_connection(conn);
+            if let Some(data) = data {
+                // a corrupt entry must not leave half its values in `msg`
+                let mut scratch = VarList::new();
+                if codec::decode_var_list(&data, &mut scratch) && codec::decode_var_list(&data, msg.get_var_list()) {
+                    self.hits += 1;
+                    return Ok(0);
+                }
+            }
+        }
+        self.misses += 1;
+
+        let start = msg.get_var_list().len();
+        let success = self.db.select(sql_cmd, msg);
+        if let Ok(0) = success {
+            if let Some(mut conn) = pool.get_redis_connection() {
+                let data = codec::encode_var_list(msg.get_var_list(), start);
+                let _ = self.store(&mut conn, &key, data, tables);
+                pool.release_redis_connection(conn);
+            }
+        }
+        success
+    }
+
+    fn store(&self, conn: &mut RedisConnection, key: &str, data: Vec<u8>, tables: &[String]) -> redis::RedisResult<()> {
+        let mut pipe = RedisPipeline::new();
+        pipe.add(key, redis::cmd("SET").arg(key).arg(data).arg("EX").arg(self.ttl).clone());
+        for table in tables {
+            let tag = self.tag_key(table);
+            pipe.add(&tag, redis::cmd("SADD").arg(&*tag).arg(key).clone());
+            pipe.add(&tag, redis::cmd("EXPIRE").arg(&*tag).arg(self.ttl).clone());
+        }
+        pipe.execute(conn)?;
+        Ok(())
+    }
+
+    /// drop every cached query tagged with one of `tables`
+    pub fn invalidate_tables(&mut self, tables: &[String]) -> redis::RedisResult<()> {
+        if tables.is_empty() {
+            return Ok(());
+        }
+        let pool = RedisPool::instance();
+        let mut conn = unwrap_or!(pool.get_redis_connection(),
+                                  fail!((redis::ErrorKind::IoError, "no redis connection")));
+        let result = self.invalidate_on(&mut conn, tables);
+        pool.release_redis_connection(conn);
+        result
+    }
+
+    fn invalidate_on(&self, conn: &mut RedisConnection, tables: &[String]) -> redis::RedisResult<()> {
+        let mut pipe = RedisPipeline::new();
+        for table in tables {
+            let tag = self.tag_key(table);
+            let keys: Vec<String> = redis::cmd("SMEMBERS").arg(&*tag).query(&mut conn.conn)?;
+            for key in keys {
+                pipe.add(&key, redis::cmd("DEL").arg(&*key).clone());
+            }
+            pipe.add(&tag, redis::cmd("DEL").arg(&*tag).clone());
+        }
+        pipe.execute(conn)?;
+        Ok(())
+    }
+
+    fn after_write(&mut self, sql_cmd: &str) {
+        let tables = table_names(sql_cmd);
+        let _ = self.invalidate_tables(&tables);
+        if self.in_transaction {
+            self.pending_tables.extend(tables);
+        }
+    }
+}
+
+/// names of the tables a statement reads or writes, lowercased and without schema or quotes
+pub fn table_names(sql_cmd: &str) -> Vec<String> {
+    let mut tables = vec![];
+    let spaced = sql_cmd.replace(',', " , ");
+    let tokens: Vec<&str> = spaced
+        .split(|c: char| c.is_whitespace() || c == '(' || c == ')' || c == ';')
+        .filter(|t| !t.is_empty())
+        .collect();
+    let mut i = 0;
+    while i < tokens.len() {
+        let keyword = tokens[i];
+        i += 1;
+        if !TABLE_KEYWORDS.iter().any(|k| keyword.eq_ignore_ascii_case(k)) {
+            continue;
+        }
+        while i < tokens.len() && tokens[i] != "," {
+            let name = tokens[i];
+            i += 1;
+            let name = name.rsplit('.').next().unwrap_or(name);
+            let name = name.trim_matches(|c| c == '`' || c == '"' || c == '\'').to_lowercase();
+            if !name.is_empty() && !tables.contains(&name) {
+                tables.push(name);
+            }
+            // a FROM list goes on after a comma, past an optional `[AS] alias`
+            if !keyword.eq_ignore_ascii_case("FROM") {
+                break;
+            }
+            let mut next = i;
+            if next < tokens.len() && tokens[next].eq_ignore_ascii_case("AS") {
+                next += 1;
+            }
+            if next < tokens.len() && tokens[next] != "," {
+                next += 1;
+            }
+            if next < tokens.len() && tokens[next] == "," {
+                i = next + 1;
+            } else {
+                break;
+            }
+        }
+    }
+    tables
+}
+
+impl<T: DbTrait> DbTrait for DbCache<T> {
+    fn select(&mut self, sql_cmd: &str, msg: &mut NetMsg) -> NetResult<i32> {
+        let tables = table_names(sql_cmd);
+        self.select_tagged(sql_cmd, &tables, msg)
+    }
+
+    fn execute(&mut self, sql_cmd: &str) -> NetResult<i32> {
+        let success = self.db.execute(sql_cmd)?;
+        if success == 0 {
+            self.after_write(sql_cmd);
+        }
+        Ok(success)
+    }
+
+    fn insert(&mut self, sql_cmd: &str, msg: &mut NetMsg) -> NetResult<i32> {
+        let success = self.db.insert(sql_cmd, msg)?;
+        if success == 0 {
+            self.after_write(sql_cmd);
+        }
+        Ok(success)
+    }
+
+    fn begin_transaction(&mut self) -> NetResult<i32> {
+        self.in_transaction = true;
+        self.pending_tables.clear();
+        self.db.begin_transaction()
+    }
+
+    /// drop the touched tables again, a reader may have cached the old rows before commit
+    fn commit_transaction(&mut self) -> NetResult<i32> {
+        let success = self.db.commit_transaction();
+        let tables = ::std::mem::replace(&mut self.pending_tables, vec![]);
+        self.in_transaction = false;
+        let _ = self.invalidate_tables(&tables);
+        success
+    }
+
+    fn rollback_transaction(&mut self) -> NetResult<i32> {
+        let success = self.db.rollback_transaction();
+        let tables = ::std::mem::replace(&mut self.pending_tables, vec![]);
+        self.in_transaction = false;
+        let _ = self.invalidate_tables(&tables);
+        success
+    }
+
+    fn get_last_insert_id(&mut self) -> u64 {
+        self.db.get_last_insert_id()
+    }
+
+    fn get_affected_rows(&mut self) -> u64 {
+        self.db.get_affected_rows()
+    }
+
+    fn get_character_set(&mut self) -> CharacterSet {
+        self.db.get_character_set()
+    }
+
+    fn is_connected(&self) -> bool {
+        self.db.is_connected()
+    }
+
+    fn get_error_code(&mut self) -> i32 {
+        self.db.get_error_code()
+    }
+
+    fn get_error_str(&mut self) -> Option<String> {
+        self.db.get_error_str()
+    }
+
+    /// streamed results are too large to cache
+    fn select_chunks(&mut self, sql_cmd: &str, chunk_rows: usize, sink: &mut dyn FnMut(NetMsg) -> bool) -> NetResult<i32> {
+        self.db.select_chunks(sql_cmd, chunk_rows, sink)
+    }
+
+    fn insert_batch(&mut self, table: &str, columns: &[&str], rows: &[VarList], result: &mut BatchResult) -> NetResult<i32> {
+        let success = self.db.insert_batch(table, columns, rows, result)?;
+        // earlier batches may have landed even when a later one failed
+        if !result.batches.is_empty() {
+            self.after_write(&format!("INSERT INTO {}", table));
+        }
+        Ok(success)
+    }
+
+    fn quote_value(&self, value: &str) -> String {
+        self.db.quote_value(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::table_names;
+
+    #[test]
+    fn select_tables() {
+        assert_eq!(table_names("SELECT * FROM user WHERE id = 1"), vec!["user"]);
+        assert_eq!(table_names("select a.id from `db`.`User` a join item b on a.id = b.uid"), vec!["user", "item"]);
+        assert_eq!(table_names("SELECT * FROM user, item"), vec!["user", "item"]);
+        assert_eq!(table_names("SELECT a, b FROM user u, `db`.item AS i, log WHERE u.id IN (1, 2)"), vec!["user", "item", "log"]);
+        assert_eq!(table_names("SELECT * FROM user ORDER BY a, b LIMIT 1, 10"), vec!["user"]);
+    }
+
+    #[test]
+    fn write_tables() {
+        assert_eq!(table_names("INSERT INTO log (a) VALUES (1)"), vec!["log"]);
+        assert_eq!(table_names("UPDATE user SET name = 'x'"), vec!["user"]);
+        assert_eq!(table_names("DELETE FROM user WHERE id = 2;"), vec!["user"]);
+        assert_eq!(table_names("TRUNCATE TABLE item"), vec!["item"]);
+    }
+
+    #[test]
+    fn duplicates_and_no_table() {
+        assert_eq!(table_names("SELECT * FROM user WHERE id IN (SELECT uid FROM user)"), vec!["user"]);
+        assert!(table_names("SELECT 1").is_empty());
+        assert!(table_names("SELECT * FROM").is_empty());
+    }
+}
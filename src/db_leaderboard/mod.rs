@@ -0,0 +1,252 @@
+use std::sync::{Arc, Mutex, Once};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use redis::RedisResult;
+use rua_net_mgr::{NetResult, ErrorKind};
+use crate::db_trait::{DbTrait, valid_name};
+use crate::db_redis::{cluster, RedisConnection, RedisPool, ScriptRegistry};
+
+static LEADERBOARD_PREFIX: &'static str = "rua_lb";
+static BEST_SCRIPT_NAME: &'static str = "rua_leaderboard_best";
+
+/// KEYS: board. ARGV: member, score, 1 when higher is better.
+/// keeps the better of the stored and submitted score
+static BEST_SCRIPT: &'static str = r"
+local old = redis.call('ZSCORE', KEYS[1], ARGV[1])
+local score = tonumber(ARGV[2])
+if old then
+    old = tonumber(old)
+    if (ARGV[3] == '1' and old >= score) or (ARGV[3] ~= '1' and old <= score) then
+        return tostring(old)
+    end
+end
+redis.call('ZADD', KEYS[1], score, ARGV[1])
+return tostring(score)";
+
+/// rows written to the snapshot table per INSERT
+static SNAPSHOT_BATCH: isize = 500;
+
+/// seconds a snapshot copy outlives a process that died before deleting it
+static SNAPSHOT_COPY_TTL: usize = 3600;
+
+static REGISTER: Once = Once::new();
+
+fn register_scripts() {
+    REGISTER.call_once(|| {
+        ScriptRegistry::instance().register(BEST_SCRIPT_NAME, BEST_SCRIPT);
+    });
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ScorePolicy {
+    /// keep the best score ever submitted
+    Best,
+    /// add every submitted score
+    Sum,
+    /// keep the last submitted score
+    Latest,
+}
+
+#[derive(Debug, Clone)]
+pub struct LeaderboardEntry {
+    /// 0 based, the best entry has rank 0
+    pub rank: u64,
+    pub member: String,
+    pub score: f64,
+}
+
+/// a ranking stored in one sorted set per season
+#[derive(Debug, Clone)]
+pub struct Leaderboard {
+    pub name: String,
+    pub season: u32,
+    pub policy: ScorePolicy,
+    /// higher scores rank first
+    pub descending: bool,
+}
+
+impl Leaderboard {
+    pub fn new(name: &str, season: u32, policy: ScorePolicy, descending: bool) -> Leaderboard {
+        register_scripts();
+        Leaderboard {
+            name: name.to_string(),
+            season,
+            policy,
+            descending,
+        }
+    }
+
+    pub fn key(&self) -> String {
+        format!("{}:{}:{}", LEADERBOARD_PREFIX, self.name, self.season)
+    }
+
+    /// submit `score` for `member` according to the policy, returns the stored score
+    pub fn submit(&self, conn: &mut RedisConnection, member: &str, score: f64) -> RedisResult<f64> {
+        let key = self.key();
+        match self.policy {
+            ScorePolicy::Sum => {
+                redis::cmd("ZINCRBY").arg(&*key).arg(score).arg(member).query(&mut conn.conn)
+            }
+            ScorePolicy::Latest => {
+                redis::cmd("ZADD").arg(&*key).arg(score).arg(member).query::<()>(&mut conn.conn)?;
+                Ok(score)
+            }
+            ScorePolicy::Best => {
+                let stored: String = ScriptRegistry::instance().invocation(BEST_SCRIPT_NAME)
+                    .key(&*key).arg(member).arg(score).arg(if self.descending { 1 } else { 0 }).invoke(conn)?;
+                Ok(stored.parse::<f64>().unwrap_or(score))
+            }
+        }
+    }
+
+    pub fn score(&self, conn: &mut RedisConnection, member: &str) -> RedisResult<Option<f64>> {
+        redis::cmd("ZSCORE").arg(self.key()).arg(member).query(&mut conn.conn)
+    }
+
+    pub fn rank(&self, conn: &mut RedisConnection, member: &str) -> RedisResult<Option<u64>> {
+        let name = if self.descending { "ZREVRANK" } else { "ZRANK" };
+        redis::cmd(name).arg(self.key()).arg(member).query(&mut conn.conn)
+    }
+
+    pub fn count(&self, conn: &mut RedisConnection) -> RedisResult<u64> {
+        redis::cmd("ZCARD").arg(self.key()).query(&mut conn.conn)
+    }
+
+    /// entries ranked `start..=stop`
+    pub fn range(&self, conn: &mut RedisConnection, start: u64, stop: u64) -> RedisResult<Vec<LeaderboardEntry>> {
+        self.range_of(conn, &self.key(), start, stop)
+    }
+
+    fn range_of(&self, conn: &mut RedisConnection, key: &str, start: u64, stop: u64) -> RedisResult<Vec<LeaderboardEntry>> {
+        let name = if self.descending { "ZREVRANGE" } else { "ZRANGE" };
+        let data: Vec<(String, f64)> = redis::cmd(name).arg(key).arg(start).arg(stop)
+            .arg("WITHSCORES").query(&mut conn.conn)?;
+        Ok(data.into_iter().enumerate().map(|(i, (member, score))| LeaderboardEntry {
+            rank: start + i as u64,
+            member,
+            score,
+        }).collect())
+    }
+
+    pub fn top(&self, conn: &mut RedisConnection, count: u64) -> RedisResult<Vec<LeaderboardEntry>> {
+        if count == 0 {
+            return Ok(vec![]);
+        }
+        self.range(conn, 0, count - 1)
+    }
+
+    /// `member` with up to `radius` neighbors on each side, empty if the member is not ranked
+    pub fn around(&self, conn: &mut RedisConnection, member: &str, radius: u64) -> RedisResult<Vec<LeaderboardEntry>> {
+        let rank = unwrap_or!(self.rank(conn, member)?, return Ok(vec![]));
+        self.range(conn, rank.saturating_sub(radius), rank + radius)
+    }
+
+    pub fn remove(&self, conn: &mut RedisConnection, member: &str) -> RedisResult<bool> {
+        redis::cmd("ZREM").arg(self.key()).arg(member).query(&mut conn.conn)
+    }
+
+    /// move on to `season`, the old season expires after `keep_seconds` or is deleted right away
+    pub fn reset_season(&mut self, conn: &mut RedisConnection, season: u32, keep_seconds: Option<usize>) -> RedisResult<()> {
+        let old_key = self.key();
+        match keep_seconds {
+            Some(seconds) => redis::cmd("EXPIRE").arg(&*old_key).arg(seconds).query::<()>(&mut conn.conn)?,
+            None => redis::cmd("DEL").arg(&*old_key).query::<()>(&mut conn.conn)?,
+        }
+        self.season = season;
+        Ok(())
+    }
+
+    /// a frozen copy of the board in the same slot, paged by `snapshot` while writers go on
+    fn copy_key(&self) -> RedisResult<String> {
+        let key = self.key();
+        let now = time::OffsetDateTime::now_utc() - time::OffsetDateTime::unix_epoch();
+        let tag = String::from_utf8_lossy(cluster::hash_key(key.as_bytes())).to_string();
+        let copy = cluster::hash_tag(&tag, &format!("snapshot:{}:{}", ::std::process::id(), now.whole_nanoseconds()));
+        if cluster::key_slot(&copy) != cluster::key_slot(&key) {
+            fail!((redis::ErrorKind::ClientError, "board name breaks the snapshot hash tag"));
+        }
+        Ok(copy)
+    }
+
+    /// copy the whole ranking into `table`, which needs the columns
+    /// `board, season, rank_no, member, score, snapshot_time`.
+    /// the board is copied first so scores changing meanwhile can not move members between pages
+    pub fn snapshot<D: DbTrait>(&self, conn: &mut RedisConnection, db: &mut D, table: &str) -> NetResult<i32> {
+        if !valid_name(table) {
+            fail!((ErrorKind::IoError, "invalid snapshot table"));
+        }
+        let copy = ok_or!(self.copy_key(), fail!((ErrorKind::IoError, "snapshot copy key error")));
+        let copied = redis::cmd("ZUNIONSTORE").arg(&*copy).arg(1).arg(self.key()).query::<()>(&mut conn.conn)
+            .and_then(|_| redis::cmd("EXPIRE").arg(&*copy).arg(SNAPSHOT_COPY_TTL).query::<()>(&mut conn.conn));
+        if copied.is_err() {
+            let _ = redis::cmd("DEL").arg(&*copy).query::<()>(&mut conn.conn);
+            fail!((ErrorKind::IoError, "copy leaderboard error"));
+        }
+        let result = self.snapshot_copy(conn, db, table, &copy);
+        let _ = redis::cmd("DEL").arg(&*copy).query::<()>(&mut conn.conn);
+        result
+    }
+
+    fn snapshot_copy<D: DbTrait>(&self, conn: &mut RedisConnection, db: &mut D, table: &str, copy: &str) -> NetResult<i32> {
+        let now = (time::OffsetDateTime::now_utc() - time::OffsetDateTime::unix_epoch()).whole_seconds();
+        let success = db.begin_transaction()?;
+        if success != 0 {
+            return Ok(success);
+        }
+
+        let mut start = 0;
+        loop {
+            let entries = ok_or!(self.range_of(conn, copy, start, start + SNAPSHOT_BATCH as u64 - 1), {
+                db.rollback_transaction()?;
+                fail!((ErrorKind::IoError, "read leaderboard error"));
+            });
+            if entries.is_empty() {
+                break;
+            }
+            // +inf / -inf / nan are valid redis scores but no sql number, those entries are left out
+            let values: Vec<String> = entries.iter().filter(|entry| entry.score.is_finite()).map(|entry| {
+                format!("({}, {}, {}, {}, {}, {})", db.quote_value(&self.name), self.season,
+                        entry.rank, db.quote_value(&entry.member), entry.score, now)
+            }).collect();
+            if !values.is_empty() {
+                let sql = format!("INSERT INTO {} (board, season, rank_no, member, score, snapshot_time) VALUES {}",
+                                  table, values.join(", "));
+                let success = db.execute(&sql)?;
+                if success != 0 {
+                    db.rollback_transaction()?;
+                    return Ok(success);
+                }
+            }
+            if (entries.len() as isize) < SNAPSHOT_BATCH {
+                break;
+            }
+            start += entries.len() as u64;
+        }
+        db.commit_transaction()
+    }
+}
+
+/// snapshot `board` into `table` every `interval` until `stop` is set.
+/// the board sits behind a mutex so a season reset is seen by the next snapshot
+pub fn spawn_snapshot<D, F>(board: Arc<Mutex<Leaderboard>>, table: &str, interval: Duration,
+                            stop: Arc<AtomicBool>, connect: F) -> JoinHandle<()>
+    where D: DbTrait, F: Fn() -> Option<D> + Send + 'static
+{
+    let table = table.to_string();
+    thread::spawn(move || {
+        let mut db = None;
+        while !stop.load(Ordering::SeqCst) {
+            thread::sleep(interval);
+            if db.is_none() {
+                db = connect();
+            }
+            let db = unwrap_or!(db.as_mut(), continue);
+            let board = board.lock().unwrap().clone();
+            let pool = RedisPool::instance();
+            let mut conn = unwrap_or!(pool.get_redis_connection(), continue);
+            let _ = board.snapshot(&mut conn, db, &table);
+            pool.release_redis_connection(conn);
+        }
+    })
+}
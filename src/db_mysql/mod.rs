@@ -1,14 +1,54 @@
-use mysql::{Conn as MysqlConn, Result as MysqlResult, Opts, Value};
+use mysql::{Conn as MysqlConn, Result as MysqlResult, Opts, OptsBuilder, QueryResult, Value};
 use time::{self};
-use crate::db_trait::DbTrait;
+use crate::db_trait::{self, valid_name, BatchResult, BatchStat, CharacterSet, DbTrait, StmtCacheStats, StreamTimeout, Watchdog, QUERY_TIMEOUT_CODE};
 use rua_net_mgr::{NetMsg, NetResult, NetConfig, ErrorKind};
-use mysql::prelude::Queryable;
+use mysql::prelude::{Protocol, Queryable};
 use std::collections::HashMap;
+use std::thread;
+use std::time::Duration;
 use rua_value_list::{ObjId, Put, VarList};
 use num_traits::{NumCast, cast};
 
+pub mod stmt_cache;
+pub mod options;
+
+pub use self::stmt_cache::StmtCache;
+pub use self::options::{ConnectError, MysqlOptions, OptionsError, TlsOptions};
+
+/// prepared statements kept per connection unless changed with `set_stmt_cache_capacity`.
+/// no more than the driver's default cache, in case a connection passed to `new` still has it on
+static DEFAULT_STMT_CACHE_CAPACITY: usize = 32;
+
+/// how `check_connect` brings a dropped connection back
+#[derive(Debug, Clone, Copy)]
+pub struct ReconnectPolicy {
+    /// attempts after the first one fails
+    pub max_retries: u32,
+    /// delay before the first retry, doubled on every further one
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+    /// seconds a connection used this recently is trusted without a ping
+    pub ping_interval: f64,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> ReconnectPolicy {
+        ReconnectPolicy {
+            max_retries: 5,
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(5),
+            ping_interval: 5.0,
+        }
+    }
+}
+
+/// packet size assumed when the server does not tell
+static DEFAULT_MAX_PACKET: usize = 4 * 1024 * 1024;
+
 static DB_RESULT_PROTO: &'static str = "msg_db_result";
 static LAST_INSERT_ID: &'static str = "sys_last_insert_id";
+static DB_RESULT_SET_PROTO: &'static str = "msg_db_result_set";
+static DB_RESULT_SET_END_PROTO: &'static str = "msg_db_result_set_end";
 
 trait Fill<T> {
     fn fill_num_val(var_list: &mut VarList, val_type: &str, val: T);
@@ -20,6 +60,26 @@ pub struct DbMysql {
     pub error: Option<mysql::Error>,
     pub is_connect: bool,
     pub last_use_time: f64,
+    pub stmt_cache: StmtCache,
+    /// used to open a fresh connection when resetting the old one fails
+    pub opts: Option<Opts>,
+    pub reconnect: ReconnectPolicy,
+    /// session statements (charset, time zone, sql_mode ...) replayed after every reconnect
+    pub init_sqls: Vec<String>,
+    /// sent with SET NAMES before the init statements of every session
+    pub character_set: CharacterSet,
+    /// @@max_allowed_packet, read once for `insert_batch`
+    pub max_packet: Option<usize>,
+    /// @@auto_increment_increment of the session, read once for `insert_batch`
+    pub auto_increment: Option<u64>,
+    /// every query running longer is killed from a side connection, needs `opts`
+    pub query_timeout: Option<Duration>,
+    /// the last query was killed by its timeout
+    pub timed_out: bool,
+    /// the session's sql_mode has NO_BACKSLASH_ESCAPES, read after every session setup.
+    /// set the mode through `init_sqls` so `quote_value` follows it
+    pub no_backslash_escapes: bool,
+    watchdog: Watchdog,
 }
 
 impl DbMysql {
@@ -31,7 +91,83 @@ impl DbMysql {
             error: None,
             is_connect: true,
             last_use_time: (time::OffsetDateTime::now_utc() - time::OffsetDateTime::unix_epoch()).as_seconds_f64(),
+            stmt_cache: StmtCache::new(DEFAULT_STMT_CACHE_CAPACITY),
+            opts: None,
+            reconnect: ReconnectPolicy::default(),
+            init_sqls: vec![],
+            character_set: CharacterSet::default(),
+            max_packet: None,
+            auto_increment: None,
+            query_timeout: None,
+            timed_out: false,
+            no_backslash_escapes: false,
+            watchdog: Watchdog::new(),
+        }
+    }
+
+    /// connect with `opts`, then set `character_set` and run `init_sqls` on the new session.
+    /// the driver's statement cache is turned off, it would close statements `stmt_cache` still holds
+    pub fn connect(opts: Opts, character_set: CharacterSet, init_sqls: Vec<String>) -> MysqlResult<DbMysql> {
+        let mut builder = OptsBuilder::from_opts(opts);
+        builder.stmt_cache_size(0);
+        let opts = Opts::from(builder);
+        let conn = MysqlConn::new(opts.clone())?;
+        let mut db = DbMysql::new(conn);
+        db.opts = Some(opts);
+        db.character_set = character_set;
+        db.init_sqls = init_sqls;
+        db.run_init_sqls()?;
+        Ok(db)
+    }
+
+    pub fn set_reconnect_policy(&mut self, policy: ReconnectPolicy) {
+        self.reconnect = policy;
+    }
+
+    /// switch the session to `character_set`, kept for every later reconnect
+    pub fn set_character_set(&mut self, character_set: CharacterSet) -> NetResult<i32> {
+        if !valid_name(character_set.charset.name()) || !valid_name(&character_set.collation) {
+            fail!((ErrorKind::IoError, "invalid character set"));
+        }
+        if !character_set.charset.is_supported() {
+            fail!((ErrorKind::IoError, "character set can not be decoded"));
+        }
+        let success = self.execute(&format!("SET NAMES {} COLLATE {}", character_set.charset.name(), character_set.collation))?;
+        if success == 0 {
+            self.character_set = character_set;
+        }
+        Ok(success)
+    }
+
+    pub fn add_init_sql(&mut self, sql_cmd: &str) {
+        self.init_sqls.push(sql_cmd.to_string());
+    }
+
+    fn run_init_sqls(&mut self) -> MysqlResult<()> {
+        self.conn.query_drop(format!("SET NAMES {} COLLATE {}", self.character_set.charset.name(), self.character_set.collation))?;
+        for sql_cmd in &self.init_sqls {
+            self.conn.query_drop(sql_cmd)?;
+        }
+        // the init statements may change the step
+        self.auto_increment = None;
+        self.read_sql_mode()
+    }
+
+    fn read_sql_mode(&mut self) -> MysqlResult<()> {
+        let sql_mode: Option<String> = self.conn.query_first("SELECT @@SESSION.sql_mode")?;
+        self.no_backslash_escapes = sql_mode.map_or(false, |sql_mode| {
+            sql_mode.split(',').any(|mode| mode.trim().eq_ignore_ascii_case("NO_BACKSLASH_ESCAPES"))
+        });
+        Ok(())
+    }
+
+    /// reset the session, or open a new connection when the old socket is gone
+    fn reconnect_once(&mut self) -> MysqlResult<()> {
+        if let Err(err) = self.conn.reset() {
+            let opts = unwrap_or!(self.opts.clone(), return Err(err));
+            self.conn = MysqlConn::new(opts)?;
         }
+        self.run_init_sqls()
     }
 
     pub fn is_io_error<T>(value: &MysqlResult<T>) -> bool {
@@ -48,153 +184,453 @@ impl DbMysql {
     }
 
     pub fn check_connect(&mut self) -> NetResult<()> {
-        if !self.conn.ping() {
-            self.is_connect = false;
-            unwrap_or!(self.conn.reset().ok(),
-                       fail!((ErrorKind::IoError, "reconnect db error")));
-            self.is_connect = true;
+        let now = (time::OffsetDateTime::now_utc() - time::OffsetDateTime::unix_epoch()).as_seconds_f64();
+        if self.is_connect && (now - self.last_use_time < self.reconnect.ping_interval || self.conn.ping()) {
+            self.last_use_time = now;
+            return Ok(());
         }
-        Ok(())
-    }
 
-    pub fn from_url_basic(url: &str) -> Option<Opts> {
-        let url = url::Url::parse(url).unwrap();
-        if url.scheme() != "mysql" {
-            return None;
+        self.is_connect = false;
+        // server side statements do not survive the new session
+        self.stmt_cache.clear();
+        let mut delay = self.reconnect.base_delay;
+        for attempt in 0..=self.reconnect.max_retries {
+            if attempt > 0 {
+                thread::sleep(delay);
+                delay = ::std::cmp::min(delay * 2, self.reconnect.max_delay);
+            }
+            if self.reconnect_once().is_ok() {
+                self.is_connect = true;
+                self.last_use_time = (time::OffsetDateTime::now_utc() - time::OffsetDateTime::unix_epoch()).as_seconds_f64();
+                return Ok(());
+            }
         }
+        fail!((ErrorKind::IoError, "reconnect db error"))
+    }
 
-        let opts = Opts::from_url(url.as_ref()).unwrap();
-        Some(opts)
+    pub fn from_url_basic(url: &str) -> Result<Opts, OptionsError> {
+        MysqlOptions::from_url(url)?.to_opts()
     }
-}
 
-impl DbTrait for DbMysql {
-    fn select(&mut self, sql_cmd: &str, msg: &mut NetMsg) -> NetResult<i32> {
-        self.check_connect()?;
-        let value = self.conn.query_iter(sql_cmd);
+    /// connect with parsed or built options
+    pub fn from_options(options: &MysqlOptions) -> Result<DbMysql, ConnectError> {
+        let opts = options.to_opts()?;
+        let mut db = DbMysql::connect(opts, options.character_set(), options.init_sqls.clone())?;
+        db.query_timeout = options.query_timeout;
+        Ok(db)
+    }
 
-        let config = NetConfig::instance();
-        let mut success: i32 = 0;
-        match value {
-            Ok(val) => {
-                self.last_insert_id = val.last_insert_id().unwrap_or(0);
-                self.affected_rows = val.affected_rows();
+    pub fn set_query_timeout(&mut self, timeout: Option<Duration>) {
+        self.query_timeout = timeout;
+    }
 
-                let mut columns = HashMap::new();
-                for (i, column) in val.columns().as_ref().iter().enumerate() {
-                    columns.insert(String::from_utf8_lossy(&column.org_name_ref()[..]).to_string(), i);
+    /// run `func` with `timeout` in place of the connection's query timeout
+    pub fn with_timeout<R, F>(&mut self, timeout: Duration, func: F) -> R
+        where F: FnOnce(&mut DbMysql) -> R
+    {
+        let old = ::std::mem::replace(&mut self.query_timeout, Some(timeout));
+        let result = func(self);
+        self.query_timeout = old;
+        result
+    }
+
+    /// run one query under the query timeout. on expiry a side connection sends KILL QUERY and the
+    /// query reports `QUERY_TIMEOUT_CODE`, the connection itself stays usable
+    fn timed<F>(&mut self, func: F) -> NetResult<i32>
+        where F: FnOnce(&mut DbMysql) -> NetResult<i32>
+    {
+        self.timed_out = false;
+        if let Some(timeout) = self.kill_timeout() {
+            // reconnect before taking the connection id, the kill has to hit the connection func uses
+            self.check_connect()?;
+            let kill = self.query_killer();
+            self.watchdog.arm(timeout, kill);
+        }
+        let result = func(self);
+        let fired = self.watchdog.disarm();
+        self.timeout_result(result, fired)
+    }
+
+    /// the query timeout, none for connections from `new` that have no options to open a side connection
+    fn kill_timeout(&self) -> Option<Duration> {
+        self.opts.as_ref().and(self.query_timeout)
+    }
+
+    /// KILL QUERY on this connection from a side connection, nothing for connections from `new`
+    fn query_killer(&self) -> impl FnOnce() + Clone + Send + 'static {
+        let side = self.opts.clone().map(|opts| (opts, self.conn.connection_id()));
+        move || {
+            if let Some((opts, connection_id)) = side {
+                if let Ok(mut side) = MysqlConn::new(opts) {
+                    let _ = side.query_drop(format!("KILL QUERY {}", connection_id));
                 }
+            }
+        }
+    }
 
-                for (_, row) in val.enumerate() {
-                    let mut row = row.unwrap();
-
-                    for (name, idx) in &columns {
-                        let field = unwrap_or!(config.get_field_by_name(name), continue);
-                        match row.take(*idx) {
-                            Some(row_val) => {
-                                match row_val {
-                                    mysql::Value::NULL => continue,
-                                    mysql::Value::Bytes(sub_val) => {
-                                        match &*field.pattern {
-                                            rua_value_list::STR_TYPE_STR => {
-                                                msg.get_var_list().put(name.clone()).put(unwrap_or!(String::from_utf8(sub_val).ok(), continue));
-                                            }
-                                            rua_value_list::STR_TYPE_OBJ => {
-                                                let str = unwrap_or!(String::from_utf8(sub_val).ok(), continue);
-                                                msg.get_var_list().put(ObjId::from(str));
-                                            }
-                                            _ =>continue,
-                                        }
-                                    }
-                                    mysql::Value::Int(sub_val) => {
-                                        msg.get_var_list().put(name.clone());
-                                        fill_num_val(msg.get_var_list(), &*field.pattern, sub_val);
-                                    }
-                                    mysql::Value::UInt(sub_val) => {
-                                        msg.get_var_list().put(name.clone());
-                                        fill_num_val(msg.get_var_list(), &*field.pattern, sub_val);
-                                    }
-                                    mysql::Value::Float(sub_val) => {
-                                        msg.get_var_list().put(name.clone());
-                                        fill_num_val(msg.get_var_list(), &*field.pattern, sub_val);
-                                    }
-                                    mysql::Value::Date(_, _, _, _, _, _, _) => {
-                                        match &*field.pattern {
-                                            rua_value_list::STR_TYPE_U32 => {
-                                                let timespec =
-                                                    mysql::from_value::<f64>(row_val);
-                                                msg.get_var_list().put(name.clone()).put(timespec as u32);
-                                            }
-                                            _ => continue,
-                                        }
-                                    }
-                                    _ => continue,
-                                }
-                            }
-                            None => continue,
-                        };
+    fn timeout_result(&mut self, result: NetResult<i32>, fired: bool) -> NetResult<i32> {
+        match result {
+            // a query that finished as the timer fired keeps its result
+            Ok(success) if fired && success != 0 => {
+                self.timed_out = true;
+                Ok(QUERY_TIMEOUT_CODE)
+            }
+            other => other,
+        }
+    }
+
+    /// remember `err`, returns the error code the trait methods report
+    fn set_error(&mut self, err: mysql::Error) -> i32 {
+        let code = match err {
+            mysql::Error::MySqlError(ref val) => val.code as i32,
+            // a broken socket is reconnected on the next call
+            mysql::Error::IoError(_) => {
+                self.is_connect = false;
+                -1
+            }
+            _ => -1,
+        };
+        self.error = Some(err);
+        code
+    }
 
+    /// above 32 only for connections opened by `connect`, see `DEFAULT_STMT_CACHE_CAPACITY`
+    pub fn set_stmt_cache_capacity(&mut self, capacity: usize) {
+        for stmt in self.stmt_cache.resize(capacity) {
+            let _ = self.conn.close(stmt);
+        }
+    }
 
-                    }
+    pub fn stmt_cache_stats(&self) -> StmtCacheStats {
+        self.stmt_cache.stats()
+    }
+
+    /// the cached statement of `sql` or a freshly prepared one, true when it came from the cache
+    fn prepare_stmt(&mut self, sql_cmd: &str) -> MysqlResult<(mysql::Statement, bool)> {
+        match self.stmt_cache.get(sql_cmd) {
+            Some(stmt) => Ok((stmt, true)),
+            None => Ok((self.conn.prep(sql_cmd)?, false)),
+        }
+    }
+
+    /// keep a freshly prepared statement once its result is consumed
+    fn cache_stmt(&mut self, sql_cmd: &str, stmt: mysql::Statement) {
+        for evicted in self.stmt_cache.insert(sql_cmd, stmt) {
+            let _ = self.conn.close(evicted);
+        }
+    }
+
+    /// prepare `sql_cmd` on the server and cache it ahead of its first use
+    pub fn prepare(&mut self, sql_cmd: &str) -> NetResult<i32> {
+        self.check_connect()?;
+        match self.prepare_stmt(sql_cmd) {
+            Ok((stmt, cached)) => {
+                if !cached {
+                    self.cache_stmt(sql_cmd, stmt);
                 }
+                Ok(0)
+            }
+            Err(err) => Ok(self.set_error(err)),
+        }
+    }
+
+    /// `select` through a cached server side statement, `params` from index 0 bind the `?` placeholders
+    pub fn select_prepared(&mut self, sql_cmd: &str, params: &VarList, msg: &mut NetMsg) -> NetResult<i32> {
+        self.timed(|db| db.run_select_prepared(sql_cmd, params, msg))
+    }
+
+    fn run_select_prepared(&mut self, sql_cmd: &str, params: &VarList, msg: &mut NetMsg) -> NetResult<i32> {
+        self.check_connect()?;
+        let (stmt, cached) = match self.prepare_stmt(sql_cmd) {
+            Ok(val) => val,
+            Err(err) => return Ok(self.set_error(err)),
+        };
+        let result = match self.conn.exec_iter(&stmt, stmt_cache::var_list_params(params, 0)) {
+            Ok(mut val) => fill_rows(&mut val, &self.character_set, msg),
+            Err(err) => Err(err),
+        };
+        let success = match result {
+            Ok((last_insert_id, affected_rows, _)) => {
+                self.last_insert_id = last_insert_id;
+                self.affected_rows = affected_rows;
                 self.error = None;
+                0
             }
-            Err(val) => {
-                match val {
-                    mysql::Error::MySqlError(ref val) => success = val.code as i32,
-                    _ => success = -1,
-                }
-                self.error = Some(val);
+            Err(err) => self.set_error(err),
+        };
+        if !cached {
+            self.cache_stmt(sql_cmd, stmt);
+        }
+        Ok(success)
+    }
+
+    /// like `select` but keeps every result set of a multi statement query, each framed as by `fill_result_sets`
+    pub fn select_multi(&mut self, sql_cmd: &str, msg: &mut NetMsg) -> NetResult<i32> {
+        self.timed(|db| db.run_select_multi(sql_cmd, msg))
+    }
+
+    fn run_select_multi(&mut self, sql_cmd: &str, msg: &mut NetMsg) -> NetResult<i32> {
+        self.check_connect()?;
+        let result = match self.conn.query_iter(sql_cmd) {
+            Ok(mut val) => {
+                let ids = (val.last_insert_id().unwrap_or(0), val.affected_rows());
+                fill_result_sets(&mut val, 0, &self.character_set, msg).map(|_| ids)
+            }
+            Err(err) => Err(err),
+        };
+        match result {
+            Ok((last_insert_id, affected_rows)) => {
+                self.last_insert_id = last_insert_id;
+                self.affected_rows = affected_rows;
+                self.error = None;
+                Ok(0)
             }
+            Err(err) => Ok(self.set_error(err)),
+        }
+    }
+
+    /// CALL `name` with `params` bound to its leading IN params and user variables for the OUT params
+    /// named in `out_names`. every result set the procedure returns is written as a framed section,
+    /// then one more section with a single row of the OUT values keyed by their names
+    pub fn call_procedure(&mut self, name: &str, params: &VarList, out_names: &[&str], msg: &mut NetMsg) -> NetResult<i32> {
+        self.timed(|db| db.run_call_procedure(name, params, out_names, msg))
+    }
+
+    fn run_call_procedure(&mut self, name: &str, params: &VarList, out_names: &[&str], msg: &mut NetMsg) -> NetResult<i32> {
+        if !valid_name(name) || out_names.iter().any(|out| !valid_name(out)) {
+            fail!((ErrorKind::IoError, "invalid procedure or param name"));
+        }
+        self.check_connect()?;
+
+        let mut args: Vec<String> = (0..params.len()).map(|_| "?".to_string()).collect();
+        args.extend(out_names.iter().map(|out| format!("@rua_out_{}", out)));
+        let sql_cmd = format!("CALL {}({})", name, args.join(", "));
+        let (stmt, cached) = match self.prepare_stmt(&sql_cmd) {
+            Ok(val) => val,
+            Err(err) => return Ok(self.set_error(err)),
+        };
+
+        // a SIGNAL inside the procedure shows up as the error of a later result set
+        let result = match self.conn.exec_iter(&stmt, stmt_cache::var_list_params(params, 0)) {
+            Ok(mut val) => {
+                let affected_rows = val.affected_rows();
+                fill_result_sets(&mut val, 0, &self.character_set, msg).map(|index| (index, affected_rows))
+            }
+            Err(err) => Err(err),
+        };
+        if !cached {
+            self.cache_stmt(&sql_cmd, stmt);
+        }
+        let index = match result {
+            Ok((index, affected_rows)) => {
+                self.affected_rows = affected_rows;
+                self.error = None;
+                index
+            }
+            Err(err) => return Ok(self.set_error(err)),
+        };
+        if out_names.is_empty() {
+            return Ok(0);
+        }
+
+        let outs: Vec<String> = out_names.iter().map(|out| format!("@rua_out_{0} AS {0}", out)).collect();
+        let result = match self.conn.query_iter(format!("SELECT {}", outs.join(", "))) {
+            Ok(mut val) => fill_result_sets(&mut val, index, &self.character_set, msg),
+            Err(err) => Err(err),
+        };
+        match result {
+            Ok(_) => Ok(0),
+            Err(err) => Ok(self.set_error(err)),
+        }
+    }
 
+    /// run one multi row INSERT of `insert_batch`. LAST_INSERT_ID() only tells the first id,
+    /// a plain multi row VALUES insert is a "simple insert" to InnoDB and gets its ids as one
+    /// run, `auto_increment_increment` apart
+    fn execute_batch(&mut self, sql_cmd: &str, rows: usize, result: &mut BatchResult) -> NetResult<i32> {
+        let success = self.execute(sql_cmd)?;
+        if success == 0 {
+            let first_id = self.last_insert_id;
+            let last_id = if first_id == 0 || rows == 0 {
+                0
+            } else {
+                first_id + (rows as u64 - 1) * self.auto_increment()
+            };
+            result.batches.push(BatchStat {
+                rows,
+                affected_rows: self.affected_rows,
+                first_id,
+                last_id,
+            });
         }
         Ok(success)
     }
 
-    fn execute(&mut self, sql_cmd: &str) -> NetResult<i32> {
+    fn max_packet(&mut self) -> usize {
+        if let Some(max_packet) = self.max_packet {
+            return max_packet;
+        }
+        let max_packet = self.conn.query_first::<usize, _>("SELECT @@max_allowed_packet")
+            .ok().and_then(|val| val).unwrap_or(DEFAULT_MAX_PACKET);
+        self.max_packet = Some(max_packet);
+        max_packet
+    }
+
+    fn auto_increment(&mut self) -> u64 {
+        if let Some(auto_increment) = self.auto_increment {
+            return auto_increment;
+        }
+        let auto_increment = self.conn.query_first::<u64, _>("SELECT @@SESSION.auto_increment_increment")
+            .ok().and_then(|val| val).unwrap_or(1).max(1);
+        self.auto_increment = Some(auto_increment);
+        auto_increment
+    }
+
+    /// `execute` through a cached server side statement
+    pub fn execute_prepared(&mut self, sql_cmd: &str, params: &VarList) -> NetResult<i32> {
+        self.timed(|db| db.run_execute_prepared(sql_cmd, params))
+    }
+
+    fn run_execute_prepared(&mut self, sql_cmd: &str, params: &VarList) -> NetResult<i32> {
+        self.check_connect()?;
+        let (stmt, cached) = match self.prepare_stmt(sql_cmd) {
+            Ok(val) => val,
+            Err(err) => return Ok(self.set_error(err)),
+        };
+        let mut success: i32 = 0;
+        match self.conn.exec_iter(&stmt, stmt_cache::var_list_params(params, 0)) {
+            Ok(val) => {
+                self.last_insert_id = val.last_insert_id().unwrap_or(0);
+                self.affected_rows = val.affected_rows();
+                self.error = None;
+            }
+            Err(val) => success = self.set_error(val),
+        }
+        if !cached {
+            self.cache_stmt(sql_cmd, stmt);
+        }
+        Ok(success)
+    }
+
+    fn run_select(&mut self, sql_cmd: &str, msg: &mut NetMsg) -> NetResult<i32> {
+        self.check_connect()?;
+        let result = match self.conn.query_iter(sql_cmd) {
+            Ok(mut val) => fill_rows(&mut val, &self.character_set, msg),
+            Err(err) => Err(err),
+        };
+        match result {
+            Ok((last_insert_id, affected_rows, _)) => {
+                self.last_insert_id = last_insert_id;
+                self.affected_rows = affected_rows;
+                self.error = None;
+                Ok(0)
+            }
+            // a KILL QUERY or a lost connection while rows stream in ends up here
+            Err(err) => Ok(self.set_error(err)),
+        }
+    }
+
+    fn run_execute(&mut self, sql_cmd: &str) -> NetResult<i32> {
         self.check_connect()?;
         let value = self.conn.query_iter(sql_cmd);
         let mut success: i32 = 0;
         match value {
             Ok(val) => {
-                self.last_insert_id = val.last_insert_id().unwrap();
+                self.last_insert_id = val.last_insert_id().unwrap_or(0);
                 self.affected_rows = val.affected_rows();
                 self.error = None;
             }
 
-            Err(val) => {
-                match val {
-                    mysql::Error::MySqlError(ref val) => success = val.code as i32,
-                    _ => success = -1,
-                }
-                self.error = Some(val);
-            }
+            Err(val) => success = self.set_error(val),
         }
         Ok(success)
     }
 
-    fn insert(&mut self, sql_cmd: &str, msg: &mut NetMsg) -> NetResult<i32> {
+    fn run_insert(&mut self, sql_cmd: &str, msg: &mut NetMsg) -> NetResult<i32> {
         self.check_connect()?;
         let value = self.conn.query_iter(sql_cmd);
         let mut success: i32 = 0;
         match value {
             Ok(val) => {
-                self.last_insert_id = val.last_insert_id().unwrap();
+                self.last_insert_id = val.last_insert_id().unwrap_or(0);
                 self.affected_rows = val.affected_rows();
                 let mut hash = HashMap::<String, Value>::new();
                 hash.insert(LAST_INSERT_ID.to_string(), Value::from(self.last_insert_id as u32));
                 msg.get_var_list().put(DB_RESULT_PROTO.to_string()).put(self.last_insert_id as u32);
                 self.error = None;
             }
-            Err(val) => {
-                match val {
-                    mysql::Error::MySqlError(ref val) => success = val.code as i32,
-                    _ => success = -1,
+            Err(val) => success = self.set_error(val),
+        }
+        Ok(success)
+    }
+
+    /// the query timeout only counts the time spent in the query, the watchdog is stopped while
+    /// the sink blocks. a cancel from the sink kills the query so the rest is not read on drop
+    fn run_select_chunks(&mut self, sql_cmd: &str, chunk_rows: usize, sink: &mut dyn FnMut(NetMsg) -> bool) -> NetResult<i32> {
+        self.check_connect()?;
+        let chunk_rows = ::std::cmp::max(chunk_rows, 1);
+        let kill = self.query_killer();
+        let kill_timeout = self.kill_timeout();
+        let mut timeout = StreamTimeout::start(&mut self.watchdog, kill_timeout, kill.clone());
+        let value = self.conn.query_iter(sql_cmd);
+        let mut row_err = None;
+        match value {
+            Ok(mut val) => {
+                self.last_insert_id = val.last_insert_id().unwrap_or(0);
+                self.affected_rows = val.affected_rows();
+                let columns = column_indexes(&val);
+                let mut msg = NetMsg::new();
+                let mut rows = 0;
+                for row in val.by_ref() {
+                    let row = match row {
+                        Ok(row) => row,
+                        Err(err) => {
+                            row_err = Some(err);
+                            break;
+                        }
+                    };
+                    fill_row(row, &columns, &self.character_set, &mut msg);
+                    rows += 1;
+                    if rows == chunk_rows {
+                        rows = 0;
+                        timeout.pause();
+                        if !sink(::std::mem::replace(&mut msg, NetMsg::new())) {
+                            kill();
+                            break;
+                        }
+                        timeout.resume();
+                    }
+                }
+                timeout.pause();
+                if rows > 0 && row_err.is_none() {
+                    sink(msg);
                 }
-                self.error = Some(val);
             }
+            Err(val) => row_err = Some(val),
         }
-        Ok(success)
+        let fired = timeout.finish();
+        let result = match row_err {
+            Some(err) => Ok(self.set_error(err)),
+            None => {
+                self.error = None;
+                Ok(0)
+            }
+        };
+        self.timeout_result(result, fired)
+    }
+}
+
+impl DbTrait for DbMysql {
+    fn select(&mut self, sql_cmd: &str, msg: &mut NetMsg) -> NetResult<i32> {
+        self.timed(|db| db.run_select(sql_cmd, msg))
+    }
+
+    fn execute(&mut self, sql_cmd: &str) -> NetResult<i32> {
+        self.timed(|db| db.run_execute(sql_cmd))
+    }
+
+    fn insert(&mut self, sql_cmd: &str, msg: &mut NetMsg) -> NetResult<i32> {
+        self.timed(|db| db.run_insert(sql_cmd, msg))
     }
 
     fn begin_transaction(&mut self) -> NetResult<i32> {
@@ -217,15 +653,18 @@ impl DbTrait for DbMysql {
         self.affected_rows
     }
 
-    fn get_character_set(&mut self) -> u8 {
-        0u8
+    fn get_character_set(&mut self) -> CharacterSet {
+        self.character_set.clone()
     }
 
     fn is_connected(&self) -> bool {
-        false
+        self.is_connect
     }
 
     fn get_error_code(&mut self) -> i32 {
+        if self.timed_out {
+            return QUERY_TIMEOUT_CODE;
+        }
         match self.error {
             Some(ref err) => {
                 match *err {
@@ -238,6 +677,9 @@ impl DbTrait for DbMysql {
     }
 
     fn get_error_str(&mut self) -> Option<String> {
+        if self.timed_out {
+            return Some("query timed out".to_string());
+        }
         match self.error {
             Some(ref err) => {
                 match *err {
@@ -248,8 +690,172 @@ impl DbTrait for DbMysql {
             None => None,
         }
     }
+
+    fn select_chunks(&mut self, sql_cmd: &str, chunk_rows: usize, sink: &mut dyn FnMut(NetMsg) -> bool) -> NetResult<i32> {
+        self.timed_out = false;
+        self.run_select_chunks(sql_cmd, chunk_rows, sink)
+    }
+
+    /// multi row INSERTs, each kept under the server's max packet size
+    fn insert_batch(&mut self, table: &str, columns: &[&str], rows: &[VarList], result: &mut BatchResult) -> NetResult<i32> {
+        if !valid_name(table) || columns.is_empty() || columns.iter().any(|column| !valid_name(column)) {
+            fail!((ErrorKind::IoError, "invalid batch table or columns"));
+        }
+        if rows.iter().any(|row| row.len() != columns.len()) {
+            fail!((ErrorKind::IoError, "batch row does not match the columns"));
+        }
+        self.check_connect()?;
+        // leave room for the packet header
+        let max_len = self.max_packet().saturating_sub(1024);
+        let columns: Vec<String> = columns.iter().map(|column| format!("`{}`", column)).collect();
+        let prefix = format!("INSERT INTO `{}` ({}) VALUES ", table, columns.join(", "));
+
+        let mut sql_cmd = prefix.clone();
+        let mut batch_rows = 0;
+        for (i, row) in rows.iter().enumerate() {
+            let values: Vec<String> = stmt_cache::var_list_params(row, 0).iter().map(|val| val.as_sql(self.no_backslash_escapes)).collect();
+            let tuple = format!("({})", values.join(", "));
+            if batch_rows > 0 && sql_cmd.len() + tuple.len() + 2 > max_len {
+                let success = self.execute_batch(&sql_cmd, batch_rows, result)?;
+                if success != 0 {
+                    return Ok(success);
+                }
+                sql_cmd = prefix.clone();
+                batch_rows = 0;
+            }
+            if batch_rows > 0 {
+                sql_cmd.push_str(", ");
+            }
+            sql_cmd.push_str(&tuple);
+            batch_rows += 1;
+            if i + 1 == rows.len() {
+                return self.execute_batch(&sql_cmd, batch_rows, result);
+            }
+        }
+        Ok(0)
+    }
+
+    fn quote_value(&self, value: &str) -> String {
+        quote_literal(value, self.no_backslash_escapes)
+    }
+}
+
+
+/// quotes are doubled, which reads the same in every sql_mode. backslashes are escaped as well
+/// unless `no_backslash_escapes`, then they are plain characters
+fn quote_literal(value: &str, no_backslash_escapes: bool) -> String {
+    if no_backslash_escapes {
+        return db_trait::quote_value(value);
+    }
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('\'');
+    for c in value.chars() {
+        match c {
+            '\'' => quoted.push_str("''"),
+            '\\' => quoted.push_str("\\\\"),
+            '\0' => quoted.push_str("\\0"),
+            _ => quoted.push(c),
+        }
+    }
+    quoted.push('\'');
+    quoted
 }
 
+/// column name to index of the current result set of `val`
+fn column_indexes<P: Protocol>(val: &QueryResult<P>) -> HashMap<String, usize> {
+    let mut columns = HashMap::new();
+    for (i, column) in val.columns().as_ref().iter().enumerate() {
+        // computed columns such as procedure out params only have their alias
+        let name = if column.org_name_ref().is_empty() { column.name_ref() } else { column.org_name_ref() };
+        columns.insert(String::from_utf8_lossy(&name[..]).to_string(), i);
+    }
+    columns
+}
+
+/// write the configured fields of one row into `msg` as field name and value pairs
+fn fill_row(mut row: mysql::Row, columns: &HashMap<String, usize>, character_set: &CharacterSet, msg: &mut NetMsg) {
+    let config = NetConfig::instance();
+    for (name, idx) in columns {
+        let field = unwrap_or!(config.get_field_by_name(name), continue);
+        match row.take(*idx) {
+            Some(row_val) => {
+                match row_val {
+                    mysql::Value::NULL => continue,
+                    mysql::Value::Bytes(sub_val) => {
+                        match &*field.pattern {
+                            rua_value_list::STR_TYPE_STR => {
+                                msg.get_var_list().put(name.clone()).put(unwrap_or!(character_set.charset.decode(sub_val), continue));
+                            }
+                            rua_value_list::STR_TYPE_OBJ => {
+                                let str = unwrap_or!(character_set.charset.decode(sub_val), continue);
+                                msg.get_var_list().put(ObjId::from(str));
+                            }
+                            _ =>continue,
+                        }
+                    }
+                    mysql::Value::Int(sub_val) => {
+                        msg.get_var_list().put(name.clone());
+                        fill_num_val(msg.get_var_list(), &*field.pattern, sub_val);
+                    }
+                    mysql::Value::UInt(sub_val) => {
+                        msg.get_var_list().put(name.clone());
+                        fill_num_val(msg.get_var_list(), &*field.pattern, sub_val);
+                    }
+                    mysql::Value::Float(sub_val) => {
+                        msg.get_var_list().put(name.clone());
+                        fill_num_val(msg.get_var_list(), &*field.pattern, sub_val);
+                    }
+                    mysql::Value::Date(_, _, _, _, _, _, _) => {
+                        match &*field.pattern {
+                            rua_value_list::STR_TYPE_U32 => {
+                                let timespec =
+                                    mysql::from_value::<f64>(row_val);
+                                msg.get_var_list().put(name.clone()).put(timespec as u32);
+                            }
+                            _ => continue,
+                        }
+                    }
+                    _ => continue,
+                }
+            }
+            None => continue,
+        };
+    }
+}
+
+/// write every row of the current result set of `val` into `msg` as field name and value pairs,
+/// returns the last insert id, affected rows and row count of that set or the first row error
+fn fill_rows<P: Protocol>(val: &mut QueryResult<P>, character_set: &CharacterSet, msg: &mut NetMsg) -> MysqlResult<(u64, u64, u32)> {
+    let last_insert_id = val.last_insert_id().unwrap_or(0);
+    let affected_rows = val.affected_rows();
+
+    let columns = column_indexes(val);
+    let mut row_count = 0;
+    for row in val.by_ref() {
+        let row = row?;
+        row_count += 1;
+        fill_row(row, &columns, character_set, msg);
+    }
+    Ok((last_insert_id, affected_rows, row_count))
+}
+
+/// every result set of `val` as its own section: `msg_db_result_set`, set index, the rows,
+/// then `msg_db_result_set_end`, row count, affected rows. returns the next free set index,
+/// or the error of the first statement that failed
+fn fill_result_sets<P: Protocol>(val: &mut QueryResult<P>, mut index: u32, character_set: &CharacterSet, msg: &mut NetMsg) -> MysqlResult<u32> {
+    while val.more_results_exists() {
+        // the status result that ends every CALL has no columns and gets no section
+        if val.columns().as_ref().is_empty() {
+            fill_rows(val, character_set, msg)?;
+            continue;
+        }
+        msg.get_var_list().put(DB_RESULT_SET_PROTO.to_string()).put(index);
+        let (_, affected_rows, row_count) = fill_rows(val, character_set, msg)?;
+        msg.get_var_list().put(DB_RESULT_SET_END_PROTO.to_string()).put(row_count).put(affected_rows);
+        index += 1;
+    }
+    Ok(index)
+}
 
 fn fill_num_val<T: NumCast>(var_list: &mut VarList, val_type: &str, val: T) {
     match &*val_type {
@@ -309,3 +915,17 @@ fn fill_num_val<T: NumCast>(var_list: &mut VarList, val_type: &str, val: T) {
 
 
 
+
+#[cfg(test)]
+mod tests {
+    use super::quote_literal;
+
+    #[test]
+    fn quote_both_modes() {
+        assert_eq!(quote_literal("it's", false), "'it''s'");
+        assert_eq!(quote_literal("a\\' OR 1=1 -- ", false), "'a\\\\'' OR 1=1 -- '");
+        assert_eq!(quote_literal("nul\0", false), "'nul\\0'");
+        assert_eq!(quote_literal("a\\' OR 1=1 -- ", true), "'a\\'' OR 1=1 -- '");
+        assert_eq!(quote_literal("c:\\dir", true), "'c:\\dir'");
+    }
+}
@@ -0,0 +1,183 @@
+use std::collections::{BTreeMap, HashMap};
+use mysql::{Statement, Value};
+use rua_value_list::{ValueType, VarList};
+use crate::db_trait::StmtCacheStats;
+
+/// prepared statements of one connection keyed by sql text, least recently used first out.
+/// for `DbMysql` the only statement cache of the connection, `DbMysql::connect` turns the driver's own off
+pub struct StmtCache<S = Statement> {
+    stmts: HashMap<String, (S, u64)>,
+    order: BTreeMap<u64, String>,
+    tick: u64,
+    pub capacity: usize,
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+}
+
+impl<S: Clone> StmtCache<S> {
+    pub fn new(capacity: usize) -> StmtCache<S> {
+        StmtCache {
+            stmts: HashMap::new(),
+            order: BTreeMap::new(),
+            tick: 0,
+            capacity,
+            hits: 0,
+            misses: 0,
+            evictions: 0,
+        }
+    }
+
+    pub fn get(&mut self, sql: &str) -> Option<S> {
+        self.tick += 1;
+        let tick = self.tick;
+        match self.stmts.get_mut(sql) {
+            Some(entry) => {
+                self.order.remove(&entry.1);
+                self.order.insert(tick, sql.to_string());
+                entry.1 = tick;
+                self.hits += 1;
+                Some(entry.0.clone())
+            }
+            None => {
+                self.misses += 1;
+                None
+            }
+        }
+    }
+
+    /// cache `stmt`, returns the statements pushed out which the caller should close on the server
+    pub fn insert(&mut self, sql: &str, stmt: S) -> Vec<S> {
+        let mut evicted = self.set_capacity(self.capacity.saturating_sub(1));
+        if self.capacity == 0 {
+            evicted.push(stmt);
+            return evicted;
+        }
+        self.tick += 1;
+        self.order.insert(self.tick, sql.to_string());
+        if let Some((old, tick)) = self.stmts.insert(sql.to_string(), (stmt, self.tick)) {
+            self.order.remove(&tick);
+            evicted.push(old);
+        }
+        evicted
+    }
+
+    /// shrink to at most `len` statements, returns the evicted ones
+    fn set_capacity(&mut self, len: usize) -> Vec<S> {
+        let mut evicted = vec![];
+        while self.stmts.len() > len {
+            let (tick, sql) = match self.order.iter().next() {
+                Some((tick, sql)) => (*tick, sql.clone()),
+                None => break,
+            };
+            self.order.remove(&tick);
+            if let Some((stmt, _)) = self.stmts.remove(&sql) {
+                evicted.push(stmt);
+                self.evictions += 1;
+            }
+        }
+        evicted
+    }
+
+    pub fn resize(&mut self, capacity: usize) -> Vec<S> {
+        self.capacity = capacity;
+        self.set_capacity(capacity)
+    }
+
+    /// forget every statement without closing, they died with the old session
+    pub fn clear(&mut self) {
+        self.stmts.clear();
+        self.order.clear();
+    }
+
+    pub fn stats(&self) -> StmtCacheStats {
+        StmtCacheStats {
+            hits: self.hits,
+            misses: self.misses,
+            evictions: self.evictions,
+            size: self.stmts.len(),
+            capacity: self.capacity,
+        }
+    }
+}
+
+/// the values of `var_list` from `start` as positional statement params
+pub fn var_list_params(var_list: &VarList, start: usize) -> Vec<Value> {
+    let mut params = vec![];
+    for i in start..var_list.len() {
+        let value = match var_list.get_type(i) {
+            ValueType::ValueTypeU8 => var_list.get_u8(i).map(Value::from),
+            ValueType::ValueTypeI8 => var_list.get_i8(i).map(Value::from),
+            ValueType::ValueTypeU16 => var_list.get_u16(i).map(Value::from),
+            ValueType::ValueTypeI16 => var_list.get_i16(i).map(Value::from),
+            ValueType::ValueTypeU32 => var_list.get_u32(i).map(Value::from),
+            ValueType::ValueTypeI32 => var_list.get_i32(i).map(Value::from),
+            ValueType::ValueTypeU64 => var_list.get_u64(i).map(Value::from),
+            ValueType::ValueTypeI64 => var_list.get_i64(i).map(Value::from),
+            ValueType::ValueTypeU128 => var_list.get_u128(i).map(|v| Value::from(v.to_string())),
+            ValueType::ValueTypeI128 => var_list.get_i128(i).map(|v| Value::from(v.to_string())),
+            ValueType::ValueTypeF32 => var_list.get_f32(i).map(Value::from),
+            ValueType::ValueTypeF64 => var_list.get_f64(i).map(Value::from),
+            ValueType::ValueTypeStr => var_list.get_str(i).map(Value::from),
+            ValueType::ValueTypeObj => var_list.get_obj(i).map(|v| Value::from(v.to_string())),
+            _ => None,
+        };
+        params.push(value.unwrap_or(Value::NULL));
+    }
+    params
+}
+
+#[cfg(test)]
+mod tests {
+    use super::StmtCache;
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let mut cache: StmtCache<u32> = StmtCache::new(2);
+        assert!(cache.insert("a", 1).is_empty());
+        assert!(cache.insert("b", 2).is_empty());
+        assert_eq!(cache.get("a"), Some(1));
+        assert_eq!(cache.insert("c", 3), vec![2]);
+        assert_eq!(cache.get("b"), None);
+        assert_eq!(cache.get("a"), Some(1));
+        assert_eq!(cache.get("c"), Some(3));
+
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses, stats.evictions, stats.size, stats.capacity), (3, 1, 1, 2, 2));
+    }
+
+    #[test]
+    fn replace_returns_old() {
+        let mut cache: StmtCache<u32> = StmtCache::new(2);
+        cache.insert("a", 1);
+        assert_eq!(cache.insert("a", 9), vec![1]);
+        assert_eq!(cache.get("a"), Some(9));
+        assert_eq!(cache.stats().size, 1);
+    }
+
+    #[test]
+    fn resize_and_zero_capacity() {
+        let mut cache: StmtCache<u32> = StmtCache::new(3);
+        cache.insert("a", 1);
+        cache.insert("b", 2);
+        cache.insert("c", 3);
+        cache.get("a");
+        assert_eq!(cache.resize(1), vec![2, 3]);
+        assert_eq!(cache.get("a"), Some(1));
+
+        assert_eq!(cache.resize(0), vec![1]);
+        assert_eq!(cache.insert("d", 4), vec![4]);
+        assert_eq!(cache.stats().size, 0);
+    }
+
+    #[test]
+    fn clear_keeps_counters() {
+        let mut cache: StmtCache<u32> = StmtCache::new(2);
+        cache.insert("a", 1);
+        cache.get("a");
+        cache.clear();
+        assert_eq!(cache.get("a"), None);
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses, stats.size), (1, 1, 0));
+    }
+}
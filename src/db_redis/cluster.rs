@@ -0,0 +1,241 @@
+use std::collections::HashMap;
+use redis::{Client, Connection, ConnectionLike, RedisError, RedisResult, Value};
+
+pub const SLOT_SIZE: u16 = 16384;
+
+fn crc16(data: &[u8]) -> u16 {
+    let mut crc: u16 = 0;
+    for byte in data {
+        crc ^= (*byte as u16) << 8;
+        for _ in 0..8 {
+            if crc & 0x8000 != 0 {
+                crc = (crc << 1) ^ 0x1021;
+            } else {
+                crc <<= 1;
+            }
+        }
+    }
+    crc
+}
+
+/// the part of the key redis hashes, the content of the first non empty `{...}` if present
+pub fn hash_key(key: &[u8]) -> &[u8] {
+    if let Some(open) = key.iter().position(|b| *b == b'{') {
+        if let Some(close) = key[open + 1..].iter().position(|b| *b == b'}') {
+            if close > 0 {
+                return &key[open + 1..open + 1 + close];
+            }
+        }
+    }
+    key
+}
+
+pub fn key_slot(key: &str) -> u16 {
+    crc16(hash_key(key.as_bytes())) % SLOT_SIZE
+}
+
+/// `key` inside the `{tag}` hash tag, keys built with the same tag always share a slot
+pub fn hash_tag(tag: &str, key: &str) -> String {
+    format!("{{{}}}:{}", tag, key)
+}
+
+pub fn same_slot(keys: &[&str]) -> bool {
+    keys_slot(keys).is_ok()
+}
+
+/// indexes of `keys` grouped by slot, groups in order of first appearance
+pub fn group_by_slot(keys: &[&str]) -> Vec<(u16, Vec<usize>)> {
+    let mut groups: Vec<(u16, Vec<usize>)> = vec![];
+    for (idx, key) in keys.iter().enumerate() {
+        let slot = key_slot(key);
+        match groups.iter_mut().find(|(s, _)| *s == slot) {
+            Some((_, indexes)) => indexes.push(idx),
+            None => groups.push((slot, vec![idx])),
+        }
+    }
+    groups
+}
+
+/// the single slot shared by `keys`, error if they spread over several
+pub fn keys_slot(keys: &[&str]) -> RedisResult<Option<u16>> {
+    let mut slot = None;
+    for key in keys {
+        let key_slot = key_slot(key);
+        match slot {
+            Some(slot) if slot != key_slot => {
+                fail!(cross_slot_error(format!("key {} hash to another slot", key)))
+            }
+            _ => slot = Some(key_slot),
+        }
+    }
+    Ok(slot)
+}
+
+pub fn cross_slot_error(detail: String) -> RedisError {
+    RedisError::from((redis::ErrorKind::ClientError, "CROSSSLOT", detail))
+}
+
+/// `(start, end, host, port)` of every master slot range, read with `CLUSTER SLOTS`
+pub fn cluster_slots<C: ConnectionLike>(conn: &mut C) -> RedisResult<Vec<(u16, u16, String, u16)>> {
+    let value: Value = redis::cmd("CLUSTER").arg("SLOTS").query(conn)?;
+    let mut result = vec![];
+    let ranges = match value {
+        Value::Bulk(ranges) => ranges,
+        _ => return Ok(result),
+    };
+    for range in ranges {
+        let items = match range {
+            Value::Bulk(items) => items,
+            _ => continue,
+        };
+        if items.len() < 3 {
+            continue;
+        }
+        let start: u16 = redis::from_redis_value(&items[0])?;
+        let end: u16 = redis::from_redis_value(&items[1])?;
+        let node = match &items[2] {
+            Value::Bulk(node) if node.len() >= 2 => node,
+            _ => continue,
+        };
+        let host: String = redis::from_redis_value(&node[0])?;
+        let port: u16 = redis::from_redis_value(&node[1])?;
+        result.push((start, end, host, port));
+    }
+    Ok(result)
+}
+
+/// url of the master that currently serves `slot`, built from `seed_url` so credentials are kept
+pub fn node_url_for_slot<C: ConnectionLike>(conn: &mut C, seed_url: &str, slot: u16) -> RedisResult<String> {
+    for (start, end, host, port) in cluster_slots(conn)? {
+        if slot < start || slot > end {
+            continue;
+        }
+        return Ok(node_url(seed_url, &host, port)?);
+    }
+    fail!((redis::ErrorKind::ClusterDown, "no node serves slot"))
+}
+
+/// url of every master, built from `seed_url`
+pub fn master_urls<C: ConnectionLike>(conn: &mut C, seed_url: &str) -> RedisResult<Vec<String>> {
+    let mut urls = vec![];
+    for (_, _, host, port) in cluster_slots(conn)? {
+        let url = node_url(seed_url, &host, port)?;
+        if !urls.contains(&url) {
+            urls.push(url);
+        }
+    }
+    Ok(urls)
+}
+
+pub fn node_url(seed_url: &str, host: &str, port: u16) -> RedisResult<String> {
+    let mut url = ok_or!(url::Url::parse(seed_url),
+                         fail!((redis::ErrorKind::InvalidClientConfig, "redis url parse error")));
+    ok_or!(url.set_host(Some(host)),
+           fail!((redis::ErrorKind::InvalidClientConfig, "redis node host error")));
+    ok_or!(url.set_port(Some(port)),
+           fail!((redis::ErrorKind::InvalidClientConfig, "redis node port error")));
+    Ok(url.to_string())
+}
+
+/// plain connections to single masters, needed for WATCH / MULTI which must stay on one node.
+/// kept per `RedisConnection` together with the slot map so they are not reopened on every call
+pub struct NodeConnections {
+    ranges: Vec<(u16, u16, String)>,
+    conns: HashMap<String, Connection>,
+}
+
+impl NodeConnections {
+    pub fn new() -> NodeConnections {
+        NodeConnections {
+            ranges: vec![],
+            conns: HashMap::new(),
+        }
+    }
+
+    /// the connection to the master of `slot` with its url, hand it back with `give_back`.
+    /// the slot map is read with `CLUSTER SLOTS` only when empty
+    pub fn take<C: ConnectionLike>(&mut self, conn: &mut C, seed_url: &str, slot: u16) -> RedisResult<(String, Connection)> {
+        if self.ranges.is_empty() {
+            for (start, end, host, port) in cluster_slots(conn)? {
+                self.ranges.push((start, end, node_url(seed_url, &host, port)?));
+            }
+        }
+        let url = unwrap_or!(self.ranges.iter().find(|(start, end, _)| slot >= *start && slot <= *end),
+                             fail!((redis::ErrorKind::ClusterDown, "no node serves slot"))).2.clone();
+        let node = self.take_url(&url)?;
+        Ok((url, node))
+    }
+
+    /// the connection to the node at `url`, hand it back with `give_back`
+    pub fn take_url(&mut self, url: &str) -> RedisResult<Connection> {
+        match self.conns.remove(url) {
+            Some(node) => Ok(node),
+            None => Client::open(url)?.get_connection(),
+        }
+    }
+
+    /// keep `node` for the next call, after an error it is closed and the slot map read again,
+    /// the slot may have moved
+    pub fn give_back(&mut self, url: String, node: Connection, ok: bool) {
+        if ok && node.is_open() {
+            self.conns.insert(url, node);
+        } else {
+            self.ranges.clear();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crc16_vectors() {
+        assert_eq!(crc16(b""), 0);
+        assert_eq!(crc16(b"123456789"), 0x31C3);
+    }
+
+    #[test]
+    fn key_slots() {
+        assert_eq!(key_slot("123456789"), 12739);
+        assert_eq!(key_slot("foo"), 12182);
+        assert_eq!(key_slot("hello"), 866);
+        assert_eq!(key_slot("{user1000}.following"), key_slot("{user1000}.followers"));
+        assert!(key_slot("user1000.following") < SLOT_SIZE);
+    }
+
+    #[test]
+    fn hash_key_tags() {
+        assert_eq!(hash_key(b"{user1000}.following"), b"user1000");
+        assert_eq!(hash_key(b"foo{bar}{zap}"), b"bar");
+        assert_eq!(hash_key(b"foo{{bar}}zap"), b"{bar");
+        assert_eq!(hash_key(b"foo{}{bar}"), b"foo{}{bar}");
+        assert_eq!(hash_key(b"{}"), b"{}");
+        assert_eq!(hash_key(b"foo{bar"), b"foo{bar");
+        assert_eq!(hash_key(b"foo}bar{"), b"foo}bar{");
+        assert_eq!(hash_key(b"plain"), b"plain");
+        assert_eq!(hash_tag("guild", "member:1"), "{guild}:member:1");
+        assert_eq!(key_slot(&hash_tag("guild", "member:1")), key_slot("guild"));
+    }
+
+    #[test]
+    fn group_order() {
+        let keys = ["{a}1", "{b}1", "{a}2", "{b}2", "{c}"];
+        let groups = group_by_slot(&keys);
+        assert_eq!(groups, vec![
+            (key_slot("a"), vec![0, 2]),
+            (key_slot("b"), vec![1, 3]),
+            (key_slot("c"), vec![4]),
+        ]);
+        assert!(group_by_slot(&[]).is_empty());
+    }
+
+    #[test]
+    fn shared_slot() {
+        assert_eq!(keys_slot(&[]).unwrap(), None);
+        assert_eq!(keys_slot(&["{a}1", "{a}2"]).unwrap(), Some(key_slot("a")));
+        assert!(keys_slot(&["{a}1", "{b}1"]).is_err());
+        assert!(same_slot(&["{a}1", "{a}2"]));
+        assert!(!same_slot(&["a", "b"]));
+    }
+}
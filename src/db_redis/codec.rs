@@ -0,0 +1,273 @@
+use rua_value_list::{ObjId, Put, ValueType, VarList};
+
+/// first byte of every encoded `VarList`, lets readers tell it apart from plain strings
+pub static VAR_LIST_MAGIC: u8 = 0xA7;
+
+const TAG_U8: u8 = 1;
+const TAG_I8: u8 = 2;
+const TAG_U16: u8 = 3;
+const TAG_I16: u8 = 4;
+const TAG_U32: u8 = 5;
+const TAG_I32: u8 = 6;
+const TAG_U64: u8 = 7;
+const TAG_I64: u8 = 8;
+const TAG_U128: u8 = 9;
+const TAG_I128: u8 = 10;
+const TAG_F32: u8 = 11;
+const TAG_F64: u8 = 12;
+const TAG_STR: u8 = 13;
+const TAG_OBJ: u8 = 14;
+
+/// conversion between our values and the bytes stored in a redis value or hash field.
+/// numbers and strings are stored as plain text so INCRBY / ZSCORE and redis-cli keep working,
+/// a `VarList` is stored in the binary layout
+///
+/// `[magic u8][count u32 le]` followed by `count` items of `[tag u8][payload]`,
+/// numbers are little endian, str and obj payloads are `[len u32 le][utf8 bytes]`
+pub trait RedisCodec: Sized {
+    fn encode(&self) -> Vec<u8>;
+    fn decode(data: &[u8]) -> Option<Self>;
+}
+
+macro_rules! text_codec {
+    ($($t:ty),*) => (
+        $(
+            impl RedisCodec for $t {
+                fn encode(&self) -> Vec<u8> {
+                    self.to_string().into_bytes()
+                }
+
+                fn decode(data: &[u8]) -> Option<$t> {
+                    ::std::str::from_utf8(data).ok()?.parse::<$t>().ok()
+                }
+            }
+        )*
+    )
+}
+
+text_codec!(u8, i8, u16, i16, u32, i32, u64, i64, u128, i128, f32, f64, String);
+
+impl RedisCodec for ObjId {
+    fn encode(&self) -> Vec<u8> {
+        self.to_string().into_bytes()
+    }
+
+    fn decode(data: &[u8]) -> Option<ObjId> {
+        let str = String::from_utf8(data.to_vec()).ok()?;
+        Some(ObjId::from(str))
+    }
+}
+
+impl RedisCodec for VarList {
+    fn encode(&self) -> Vec<u8> {
+        encode_var_list(self, 0)
+    }
+
+    fn decode(data: &[u8]) -> Option<VarList> {
+        let mut var_list = VarList::new();
+        if !decode_var_list(data, &mut var_list) {
+            return None;
+        }
+        Some(var_list)
+    }
+}
+
+/// encode the values of `var_list` from index `start`, used to cache only what one query appended
+pub fn encode_var_list(var_list: &VarList, start: usize) -> Vec<u8> {
+    let mut buffer = vec![VAR_LIST_MAGIC];
+    let mut count: u32 = 0;
+    buffer.extend_from_slice(&count.to_le_bytes());
+    for i in start..var_list.len() {
+        let ok = match var_list.get_type(i) {
+            ValueType::ValueTypeU8 => write_num(&mut buffer, TAG_U8, var_list.get_u8(i).map(|v| v.to_le_bytes())),
+            ValueType::ValueTypeI8 => write_num(&mut buffer, TAG_I8, var_list.get_i8(i).map(|v| v.to_le_bytes())),
+            ValueType::ValueTypeU16 => write_num(&mut buffer, TAG_U16, var_list.get_u16(i).map(|v| v.to_le_bytes())),
+            ValueType::ValueTypeI16 => write_num(&mut buffer, TAG_I16, var_list.get_i16(i).map(|v| v.to_le_bytes())),
+            ValueType::ValueTypeU32 => write_num(&mut buffer, TAG_U32, var_list.get_u32(i).map(|v| v.to_le_bytes())),
+            ValueType::ValueTypeI32 => write_num(&mut buffer, TAG_I32, var_list.get_i32(i).map(|v| v.to_le_bytes())),
+            ValueType::ValueTypeU64 => write_num(&mut buffer, TAG_U64, var_list.get_u64(i).map(|v| v.to_le_bytes())),
+            ValueType::ValueTypeI64 => write_num(&mut buffer, TAG_I64, var_list.get_i64(i).map(|v| v.to_le_bytes())),
+            ValueType::ValueTypeU128 => write_num(&mut buffer, TAG_U128, var_list.get_u128(i).map(|v| v.to_le_bytes())),
+            ValueType::ValueTypeI128 => write_num(&mut buffer, TAG_I128, var_list.get_i128(i).map(|v| v.to_le_bytes())),
+            ValueType::ValueTypeF32 => write_num(&mut buffer, TAG_F32, var_list.get_f32(i).map(|v| v.to_bits().to_le_bytes())),
+            ValueType::ValueTypeF64 => write_num(&mut buffer, TAG_F64, var_list.get_f64(i).map(|v| v.to_bits().to_le_bytes())),
+            ValueType::ValueTypeStr => write_bytes(&mut buffer, TAG_STR, var_list.get_str(i).map(|v| v.as_bytes().to_vec())),
+            ValueType::ValueTypeObj => write_bytes(&mut buffer, TAG_OBJ, var_list.get_obj(i).map(|v| v.to_string().into_bytes())),
+            _ => false,
+        };
+        if ok {
+            count += 1;
+        }
+    }
+    buffer[1..5].copy_from_slice(&count.to_le_bytes());
+    buffer
+}
+
+/// append the values encoded in `data` to `var_list`, false if `data` is not an encoded `VarList`
+pub fn decode_var_list(data: &[u8], var_list: &mut VarList) -> bool {
+    decode_items(data, var_list).is_some()
+}
+
+fn decode_items(data: &[u8], var_list: &mut VarList) -> Option<()> {
+    if data.len() < 5 || data[0] != VAR_LIST_MAGIC {
+        return None;
+    }
+    let count = u32::from_le_bytes(read_array(data, 1)?);
+    let mut pos = 5;
+    for _ in 0..count {
+        let tag = *data.get(pos)?;
+        pos += 1;
+        match tag {
+            TAG_U8 => { var_list.put(u8::from_le_bytes(read_array(data, pos)?)); pos += 1; }
+            TAG_I8 => { var_list.put(i8::from_le_bytes(read_array(data, pos)?)); pos += 1; }
+            TAG_U16 => { var_list.put(u16::from_le_bytes(read_array(data, pos)?)); pos += 2; }
+            TAG_I16 => { var_list.put(i16::from_le_bytes(read_array(data, pos)?)); pos += 2; }
+            TAG_U32 => { var_list.put(u32::from_le_bytes(read_array(data, pos)?)); pos += 4; }
+            TAG_I32 => { var_list.put(i32::from_le_bytes(read_array(data, pos)?)); pos += 4; }
+            TAG_U64 => { var_list.put(u64::from_le_bytes(read_array(data, pos)?)); pos += 8; }
+            TAG_I64 => { var_list.put(i64::from_le_bytes(read_array(data, pos)?)); pos += 8; }
+            TAG_U128 => { var_list.put(u128::from_le_bytes(read_array(data, pos)?)); pos += 16; }
+            TAG_I128 => { var_list.put(i128::from_le_bytes(read_array(data, pos)?)); pos += 16; }
+            TAG_F32 => { var_list.put(f32::from_bits(u32::from_le_bytes(read_array(data, pos)?))); pos += 4; }
+            TAG_F64 => { var_list.put(f64::from_bits(u64::from_le_bytes(read_array(data, pos)?))); pos += 8; }
+            TAG_STR | TAG_OBJ => {
+                let len = u32::from_le_bytes(read_array(data, pos)?) as usize;
+                pos += 4;
+                let bytes = data.get(pos..pos + len)?;
+                pos += len;
+                let str = String::from_utf8(bytes.to_vec()).ok()?;
+                if tag == TAG_STR {
+                    var_list.put(str);
+                } else {
+                    var_list.put(ObjId::from(str));
+                }
+            }
+            _ => return None,
+        }
+    }
+    Some(())
+}
+
+fn write_num<A: AsRef<[u8]>>(buffer: &mut Vec<u8>, tag: u8, bytes: Option<A>) -> bool {
+    let bytes = unwrap_or!(bytes, return false);
+    buffer.push(tag);
+    buffer.extend_from_slice(bytes.as_ref());
+    true
+}
+
+fn write_bytes(buffer: &mut Vec<u8>, tag: u8, bytes: Option<Vec<u8>>) -> bool {
+    let bytes = unwrap_or!(bytes, return false);
+    buffer.push(tag);
+    buffer.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+    buffer.extend_from_slice(&bytes);
+    true
+}
+
+fn read_array<A: Default + AsMut<[u8]>>(data: &[u8], pos: usize) -> Option<A> {
+    let mut array = A::default();
+    let len = array.as_mut().len();
+    array.as_mut().copy_from_slice(data.get(pos..pos + len)?);
+    Some(array)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn every_type() -> VarList {
+        let mut var_list = VarList::new();
+        var_list.put(7u8);
+        var_list.put(-7i8);
+        var_list.put(700u16);
+        var_list.put(-700i16);
+        var_list.put(70_000u32);
+        var_list.put(-70_000i32);
+        var_list.put(7_000_000_000u64);
+        var_list.put(-7_000_000_000i64);
+        var_list.put(u128::max_value());
+        var_list.put(i128::min_value());
+        var_list.put(1.5f32);
+        var_list.put(-2.25f64);
+        var_list.put("héllo".to_string());
+        var_list.put(ObjId::from("obj-1".to_string()));
+        var_list
+    }
+
+    #[test]
+    fn var_list_round_trip() {
+        let data = every_type().encode();
+        assert_eq!(data[0], VAR_LIST_MAGIC);
+        assert_eq!(u32::from_le_bytes(read_array(&data, 1).unwrap()), 14);
+
+        let var_list = VarList::decode(&data).unwrap();
+        assert_eq!(var_list.len(), 14);
+        assert_eq!(var_list.get_u8(0), Some(7));
+        assert_eq!(var_list.get_i8(1), Some(-7));
+        assert_eq!(var_list.get_u16(2), Some(700));
+        assert_eq!(var_list.get_i16(3), Some(-700));
+        assert_eq!(var_list.get_u32(4), Some(70_000));
+        assert_eq!(var_list.get_i32(5), Some(-70_000));
+        assert_eq!(var_list.get_u64(6), Some(7_000_000_000));
+        assert_eq!(var_list.get_i64(7), Some(-7_000_000_000));
+        assert_eq!(var_list.get_u128(8), Some(u128::max_value()));
+        assert_eq!(var_list.get_i128(9), Some(i128::min_value()));
+        assert_eq!(var_list.get_f32(10), Some(1.5));
+        assert_eq!(var_list.get_f64(11), Some(-2.25));
+        assert_eq!(var_list.get_str(12).map(|v| v.to_string()), Some("héllo".to_string()));
+        assert_eq!(var_list.get_obj(13).map(|v| v.to_string()), Some("obj-1".to_string()));
+        assert_eq!(var_list.encode(), data);
+    }
+
+    #[test]
+    fn encode_from_start() {
+        let data = encode_var_list(&every_type(), 12);
+        let var_list = VarList::decode(&data).unwrap();
+        assert_eq!(var_list.len(), 2);
+        assert_eq!(var_list.get_str(0).map(|v| v.to_string()), Some("héllo".to_string()));
+    }
+
+    #[test]
+    fn empty_var_list() {
+        let data = VarList::new().encode();
+        assert_eq!(data, vec![VAR_LIST_MAGIC, 0, 0, 0, 0]);
+        assert_eq!(VarList::decode(&data).map(|v| v.len()), Some(0));
+    }
+
+    #[test]
+    fn truncated_input() {
+        let data = every_type().encode();
+        for len in 0..data.len() {
+            assert!(VarList::decode(&data[..len]).is_none(), "decoded {} of {} bytes", len, data.len());
+        }
+    }
+
+    #[test]
+    fn bad_magic() {
+        let mut data = every_type().encode();
+        data[0] = b'x';
+        assert!(VarList::decode(&data).is_none());
+        assert!(VarList::decode(b"plain string").is_none());
+    }
+
+    #[test]
+    fn unknown_tag_and_bad_utf8() {
+        let mut data = vec![VAR_LIST_MAGIC, 1, 0, 0, 0, 99];
+        assert!(VarList::decode(&data).is_none());
+
+        data.truncate(5);
+        data.push(TAG_STR);
+        data.extend_from_slice(&2u32.to_le_bytes());
+        data.extend_from_slice(&[0xff, 0xfe]);
+        assert!(VarList::decode(&data).is_none());
+    }
+
+    #[test]
+    fn text_values() {
+        assert_eq!(42i64.encode(), b"42".to_vec());
+        assert_eq!(i64::decode(b"-42"), Some(-42));
+        assert_eq!(f64::decode(b"2.5"), Some(2.5));
+        assert_eq!(u8::decode(b"256"), None);
+        assert_eq!(String::decode(b"abc"), Some("abc".to_string()));
+        assert_eq!(String::decode(&[0xff]), None);
+    }
+}
@@ -0,0 +1,200 @@
+use std::collections::HashMap;
+use redis::{RedisError, RedisResult};
+use super::RedisConnection;
+use super::codec::RedisCodec;
+use super::cluster;
+
+fn decode_error() -> RedisError {
+    RedisError::from((redis::ErrorKind::TypeError, "redis value can not decode"))
+}
+
+fn decode_opt<T: RedisCodec>(data: Option<Vec<u8>>) -> RedisResult<Option<T>> {
+    match data {
+        Some(data) => Ok(Some(unwrap_or!(T::decode(&data), fail!(decode_error())))),
+        None => Ok(None),
+    }
+}
+
+fn decode_vec<T: RedisCodec>(data: Vec<Vec<u8>>) -> RedisResult<Vec<T>> {
+    let mut result = Vec::with_capacity(data.len());
+    for val in data {
+        result.push(unwrap_or!(T::decode(&val), fail!(decode_error())));
+    }
+    Ok(result)
+}
+
+/// typed helpers, every value goes through `RedisCodec` so callers work with
+/// `VarList`, `ObjId` and numbers instead of raw bytes
+impl RedisConnection {
+    pub fn set_value<T: RedisCodec>(&mut self, key: &str, value: &T) -> RedisResult<()> {
+        redis::cmd("SET").arg(key).arg(value.encode()).query(&mut self.conn)
+    }
+
+    pub fn set_value_ex<T: RedisCodec>(&mut self, key: &str, value: &T, seconds: usize) -> RedisResult<()> {
+        redis::cmd("SET").arg(key).arg(value.encode()).arg("EX").arg(seconds).query(&mut self.conn)
+    }
+
+    pub fn get_value<T: RedisCodec>(&mut self, key: &str) -> RedisResult<Option<T>> {
+        let data: Option<Vec<u8>> = redis::cmd("GET").arg(key).query(&mut self.conn)?;
+        decode_opt(data)
+    }
+
+    pub fn del_key(&mut self, key: &str) -> RedisResult<u64> {
+        redis::cmd("DEL").arg(key).query(&mut self.conn)
+    }
+
+    pub fn expire_key(&mut self, key: &str, seconds: usize) -> RedisResult<bool> {
+        redis::cmd("EXPIRE").arg(key).arg(seconds).query(&mut self.conn)
+    }
+
+    pub fn hset_value<T: RedisCodec>(&mut self, key: &str, field: &str, value: &T) -> RedisResult<u64> {
+        redis::cmd("HSET").arg(key).arg(field).arg(value.encode()).query(&mut self.conn)
+    }
+
+    pub fn hget_value<T: RedisCodec>(&mut self, key: &str, field: &str) -> RedisResult<Option<T>> {
+        let data: Option<Vec<u8>> = redis::cmd("HGET").arg(key).arg(field).query(&mut self.conn)?;
+        decode_opt(data)
+    }
+
+    pub fn hdel_field(&mut self, key: &str, field: &str) -> RedisResult<u64> {
+        redis::cmd("HDEL").arg(key).arg(field).query(&mut self.conn)
+    }
+
+    pub fn hgetall_values<T: RedisCodec>(&mut self, key: &str) -> RedisResult<HashMap<String, T>> {
+        let data: HashMap<String, Vec<u8>> = redis::cmd("HGETALL").arg(key).query(&mut self.conn)?;
+        let mut result = HashMap::new();
+        for (field, val) in data {
+            result.insert(field, unwrap_or!(T::decode(&val), fail!(decode_error())));
+        }
+        Ok(result)
+    }
+
+    pub fn lpush_value<T: RedisCodec>(&mut self, key: &str, value: &T) -> RedisResult<u64> {
+        redis::cmd("LPUSH").arg(key).arg(value.encode()).query(&mut self.conn)
+    }
+
+    pub fn rpush_value<T: RedisCodec>(&mut self, key: &str, value: &T) -> RedisResult<u64> {
+        redis::cmd("RPUSH").arg(key).arg(value.encode()).query(&mut self.conn)
+    }
+
+    pub fn lpop_value<T: RedisCodec>(&mut self, key: &str) -> RedisResult<Option<T>> {
+        let data: Option<Vec<u8>> = redis::cmd("LPOP").arg(key).query(&mut self.conn)?;
+        decode_opt(data)
+    }
+
+    pub fn rpop_value<T: RedisCodec>(&mut self, key: &str) -> RedisResult<Option<T>> {
+        let data: Option<Vec<u8>> = redis::cmd("RPOP").arg(key).query(&mut self.conn)?;
+        decode_opt(data)
+    }
+
+    pub fn lrange_values<T: RedisCodec>(&mut self, key: &str, start: isize, stop: isize) -> RedisResult<Vec<T>> {
+        let data: Vec<Vec<u8>> = redis::cmd("LRANGE").arg(key).arg(start).arg(stop).query(&mut self.conn)?;
+        decode_vec(data)
+    }
+
+    pub fn sadd_value<T: RedisCodec>(&mut self, key: &str, member: &T) -> RedisResult<bool> {
+        redis::cmd("SADD").arg(key).arg(member.encode()).query(&mut self.conn)
+    }
+
+    pub fn srem_value<T: RedisCodec>(&mut self, key: &str, member: &T) -> RedisResult<bool> {
+        redis::cmd("SREM").arg(key).arg(member.encode()).query(&mut self.conn)
+    }
+
+    pub fn sismember_value<T: RedisCodec>(&mut self, key: &str, member: &T) -> RedisResult<bool> {
+        redis::cmd("SISMEMBER").arg(key).arg(member.encode()).query(&mut self.conn)
+    }
+
+    pub fn smembers_values<T: RedisCodec>(&mut self, key: &str) -> RedisResult<Vec<T>> {
+        let data: Vec<Vec<u8>> = redis::cmd("SMEMBERS").arg(key).query(&mut self.conn)?;
+        decode_vec(data)
+    }
+
+    pub fn zadd_value<T: RedisCodec>(&mut self, key: &str, member: &T, score: f64) -> RedisResult<u64> {
+        redis::cmd("ZADD").arg(key).arg(score).arg(member.encode()).query(&mut self.conn)
+    }
+
+    pub fn zincrby_value<T: RedisCodec>(&mut self, key: &str, member: &T, delta: f64) -> RedisResult<f64> {
+        redis::cmd("ZINCRBY").arg(key).arg(delta).arg(member.encode()).query(&mut self.conn)
+    }
+
+    pub fn zrem_value<T: RedisCodec>(&mut self, key: &str, member: &T) -> RedisResult<bool> {
+        redis::cmd("ZREM").arg(key).arg(member.encode()).query(&mut self.conn)
+    }
+
+    pub fn zscore_value<T: RedisCodec>(&mut self, key: &str, member: &T) -> RedisResult<Option<f64>> {
+        redis::cmd("ZSCORE").arg(key).arg(member.encode()).query(&mut self.conn)
+    }
+
+    pub fn zrank_value<T: RedisCodec>(&mut self, key: &str, member: &T, reverse: bool) -> RedisResult<Option<u64>> {
+        let name = if reverse { "ZREVRANK" } else { "ZRANK" };
+        redis::cmd(name).arg(key).arg(member.encode()).query(&mut self.conn)
+    }
+
+    pub fn zrange_values<T: RedisCodec>(&mut self, key: &str, start: isize, stop: isize, reverse: bool) -> RedisResult<Vec<(T, f64)>> {
+        let name = if reverse { "ZREVRANGE" } else { "ZRANGE" };
+        let data: Vec<(Vec<u8>, f64)> = redis::cmd(name).arg(key).arg(start).arg(stop).arg("WITHSCORES").query(&mut self.conn)?;
+        let mut result = Vec::with_capacity(data.len());
+        for (member, score) in data {
+            result.push((unwrap_or!(T::decode(&member), fail!(decode_error())), score));
+        }
+        Ok(result)
+    }
+
+    /// key groups a multi key command can run on, one group per slot in cluster mode
+    fn slot_groups(&self, keys: &[&str]) -> Vec<Vec<usize>> {
+        if keys.is_empty() {
+            vec![]
+        } else if self.is_cluster() {
+            cluster::group_by_slot(keys).into_iter().map(|(_, indexes)| indexes).collect()
+        } else {
+            vec![(0..keys.len()).collect()]
+        }
+    }
+
+    /// MGET split per slot, values come back in the order of `keys`
+    pub fn mget_values<T: RedisCodec>(&mut self, keys: &[&str]) -> RedisResult<Vec<Option<T>>> {
+        let mut result: Vec<Option<T>> = Vec::with_capacity(keys.len());
+        result.resize_with(keys.len(), || None);
+        for indexes in self.slot_groups(keys) {
+            let group: Vec<&str> = indexes.iter().map(|i| keys[*i]).collect();
+            let data: Vec<Option<Vec<u8>>> = redis::cmd("MGET").arg(&group).query(&mut self.conn)?;
+            for (idx, val) in indexes.into_iter().zip(data.into_iter()) {
+                result[idx] = decode_opt(val)?;
+            }
+        }
+        Ok(result)
+    }
+
+    /// MSET split per slot, only atomic per slot in cluster mode
+    pub fn mset_values<T: RedisCodec>(&mut self, pairs: &[(&str, T)]) -> RedisResult<()> {
+        let keys: Vec<&str> = pairs.iter().map(|(key, _)| *key).collect();
+        for indexes in self.slot_groups(&keys) {
+            let mut cmd = redis::cmd("MSET");
+            for idx in indexes {
+                cmd.arg(pairs[idx].0).arg(pairs[idx].1.encode());
+            }
+            cmd.query::<()>(&mut self.conn)?;
+        }
+        Ok(())
+    }
+
+    /// DEL split per slot, returns the number of keys removed
+    pub fn del_keys(&mut self, keys: &[&str]) -> RedisResult<u64> {
+        let mut deleted = 0;
+        for indexes in self.slot_groups(keys) {
+            let group: Vec<&str> = indexes.iter().map(|i| keys[*i]).collect();
+            deleted += redis::cmd("DEL").arg(&group).query::<u64>(&mut self.conn)?;
+        }
+        Ok(deleted)
+    }
+
+    /// EXISTS split per slot, returns how many of `keys` exist
+    pub fn exists_keys(&mut self, keys: &[&str]) -> RedisResult<u64> {
+        let mut exists = 0;
+        for indexes in self.slot_groups(keys) {
+            let group: Vec<&str> = indexes.iter().map(|i| keys[*i]).collect();
+            exists += redis::cmd("EXISTS").arg(&group).query::<u64>(&mut self.conn)?;
+        }
+        Ok(exists)
+    }
+}
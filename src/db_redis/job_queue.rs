@@ -0,0 +1,245 @@
+use std::sync::Once;
+use redis::{RedisResult, Value};
+use rua_value_list::VarList;
+use super::{RedisConnection, RedisPipeline, ScriptRegistry};
+use super::codec::{self, RedisCodec};
+
+static CLAIM_SCRIPT_NAME: &'static str = "rua_job_claim";
+static ACK_SCRIPT_NAME: &'static str = "rua_job_ack";
+static FAIL_SCRIPT_NAME: &'static str = "rua_job_fail";
+
+/// KEYS: delayed, claimed, payload, attempts, interval, dead. ARGV: now ms, count, visibility ms,
+/// max attempts, backoff ms. first treats timed out claims like failures, then claims due jobs
+static CLAIM_SCRIPT: &'static str = r"
+local now = tonumber(ARGV[1])
+local expired = redis.call('ZRANGEBYSCORE', KEYS[2], '-inf', now)
+for _, id in ipairs(expired) do
+    redis.call('ZREM', KEYS[2], id)
+    local attempts = tonumber(redis.call('HGET', KEYS[4], id) or '0')
+    if attempts >= tonumber(ARGV[4]) then
+        local payload = redis.call('HGET', KEYS[3], id)
+        if payload then
+            redis.call('LPUSH', KEYS[6], id .. ':' .. payload)
+        end
+        redis.call('HDEL', KEYS[3], id)
+        redis.call('HDEL', KEYS[4], id)
+        redis.call('HDEL', KEYS[5], id)
+    else
+        local delay = tonumber(ARGV[5]) * math.pow(2, math.max(attempts - 1, 0))
+        redis.call('ZADD', KEYS[1], now + delay, id)
+    end
+end
+local ids = redis.call('ZRANGEBYSCORE', KEYS[1], '-inf', now, 'LIMIT', 0, tonumber(ARGV[2]))
+local result = {}
+for _, id in ipairs(ids) do
+    redis.call('ZREM', KEYS[1], id)
+    redis.call('ZADD', KEYS[2], now + tonumber(ARGV[3]), id)
+    local attempts = redis.call('HINCRBY', KEYS[4], id, 1)
+    local payload = redis.call('HGET', KEYS[3], id)
+    if payload then
+        table.insert(result, id)
+        table.insert(result, payload)
+        table.insert(result, attempts)
+    end
+end
+return result";
+
+/// KEYS: delayed, claimed, payload, attempts, interval. ARGV: id, now ms.
+/// recurring jobs are scheduled again, the others are removed
+static ACK_SCRIPT: &'static str = r"
+if redis.call('ZREM', KEYS[2], ARGV[1]) == 0 then
+    return 0
+end
+local interval = redis.call('HGET', KEYS[5], ARGV[1])
+if interval then
+    redis.call('HSET', KEYS[4], ARGV[1], 0)
+    redis.call('ZADD', KEYS[1], tonumber(ARGV[2]) + tonumber(interval), ARGV[1])
+else
+    redis.call('HDEL', KEYS[3], ARGV[1])
+    redis.call('HDEL', KEYS[4], ARGV[1])
+end
+return 1";
+
+/// KEYS: delayed, claimed, payload, attempts, interval, dead. ARGV: id, now ms, max attempts, backoff ms.
+/// retried with exponential backoff, moved to the dead list after `max attempts`, right away with 0
+static FAIL_SCRIPT: &'static str = r"
+if redis.call('ZREM', KEYS[2], ARGV[1]) == 0 then
+    return 0
+end
+local attempts = tonumber(redis.call('HGET', KEYS[4], ARGV[1]) or '0')
+if attempts >= tonumber(ARGV[3]) then
+    local payload = redis.call('HGET', KEYS[3], ARGV[1])
+    if payload then
+        redis.call('LPUSH', KEYS[6], ARGV[1] .. ':' .. payload)
+    end
+    redis.call('HDEL', KEYS[3], ARGV[1])
+    redis.call('HDEL', KEYS[4], ARGV[1])
+    redis.call('HDEL', KEYS[5], ARGV[1])
+    return 2
+end
+local delay = tonumber(ARGV[4]) * math.pow(2, math.max(attempts - 1, 0))
+redis.call('ZADD', KEYS[1], tonumber(ARGV[2]) + delay, ARGV[1])
+return 1";
+
+static REGISTER: Once = Once::new();
+
+fn register_scripts() {
+    REGISTER.call_once(|| {
+        let registry = ScriptRegistry::instance();
+        registry.register(CLAIM_SCRIPT_NAME, CLAIM_SCRIPT);
+        registry.register(ACK_SCRIPT_NAME, ACK_SCRIPT);
+        registry.register(FAIL_SCRIPT_NAME, FAIL_SCRIPT);
+    });
+}
+
+fn now_millis() -> u64 {
+    (time::OffsetDateTime::now_utc() - time::OffsetDateTime::unix_epoch()).whole_milliseconds() as u64
+}
+
+pub struct Job {
+    pub id: u64,
+    pub payload: VarList,
+    /// 1 on the first delivery
+    pub attempts: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum JobFailure {
+    /// scheduled again after the backoff
+    Retried,
+    /// moved to the dead letter list
+    Dead,
+    /// the claim already timed out and the job went back to the queue
+    NotClaimed,
+}
+
+/// delayed and recurring jobs on sorted sets. a claimed job stays invisible for the
+/// visibility timeout and comes back if the worker neither acks nor fails it in time.
+/// every key carries the `{name}` hash tag so the scripts run on one cluster slot
+pub struct JobQueue {
+    pub name: String,
+    pub visibility_ms: u64,
+    pub max_attempts: u32,
+    /// first retry delay, doubled on every further attempt
+    pub backoff_ms: u64,
+}
+
+impl JobQueue {
+    pub fn new(name: &str) -> JobQueue {
+        register_scripts();
+        JobQueue {
+            name: name.to_string(),
+            visibility_ms: 30_000,
+            max_attempts: 5,
+            backoff_ms: 1_000,
+        }
+    }
+
+    fn key(&self, part: &str) -> String {
+        format!("rua_job:{{{}}}:{}", self.name, part)
+    }
+
+    /// run `payload` after `delay_ms`, and every `interval_ms` after that when recurring
+    pub fn push(&self, conn: &mut RedisConnection, payload: &VarList, delay_ms: u64, interval_ms: Option<u64>) -> RedisResult<u64> {
+        let id: u64 = redis::cmd("INCR").arg(self.key("seq")).query(&mut conn.conn)?;
+        let mut pipe = RedisPipeline::new();
+        pipe.atomic();
+        let (payload_key, attempts_key, delayed_key) = (self.key("payload"), self.key("attempts"), self.key("delayed"));
+        pipe.add(&payload_key, redis::cmd("HSET").arg(&*payload_key).arg(id).arg(payload.encode()).clone());
+        pipe.add(&attempts_key, redis::cmd("HSET").arg(&*attempts_key).arg(id).arg(0).clone());
+        if let Some(interval) = interval_ms {
+            let interval_key = self.key("interval");
+            pipe.add(&interval_key, redis::cmd("HSET").arg(&*interval_key).arg(id).arg(interval).clone());
+        }
+        pipe.add(&delayed_key, redis::cmd("ZADD").arg(&*delayed_key).arg(now_millis() + delay_ms).arg(id).clone());
+        pipe.execute(conn)?;
+        Ok(id)
+    }
+
+    /// claim up to `count` due jobs. claims that timed out count as failed attempts, so a job
+    /// that keeps crashing its worker ends up in the dead list too
+    pub fn claim(&self, conn: &mut RedisConnection, count: usize) -> RedisResult<Vec<Job>> {
+        let value: Value = ScriptRegistry::instance().invocation(CLAIM_SCRIPT_NAME)
+            .key(self.key("delayed")).key(self.key("claimed")).key(self.key("payload"))
+            .key(self.key("attempts")).key(self.key("interval")).key(self.key("dead"))
+            .arg(now_millis()).arg(count).arg(self.visibility_ms)
+            .arg(self.max_attempts).arg(self.backoff_ms).invoke(conn)?;
+        let items = match value {
+            Value::Bulk(items) => items,
+            _ => return Ok(vec![]),
+        };
+        let mut jobs = vec![];
+        for item in items.chunks(3) {
+            if item.len() < 3 {
+                continue;
+            }
+            let id: u64 = redis::from_redis_value(&item[0])?;
+            let data: Vec<u8> = redis::from_redis_value(&item[1])?;
+            let attempts: u32 = redis::from_redis_value(&item[2])?;
+            let payload = unwrap_or!(VarList::decode(&data), {
+                // would be delivered again forever, no worker can run it
+                self.fail_job(conn, id, 0)?;
+                continue;
+            });
+            jobs.push(Job { id, payload, attempts });
+        }
+        Ok(jobs)
+    }
+
+    /// the job is done, false if its claim had already timed out
+    pub fn ack(&self, conn: &mut RedisConnection, job: &Job) -> RedisResult<bool> {
+        let done: i64 = ScriptRegistry::instance().invocation(ACK_SCRIPT_NAME)
+            .key(self.key("delayed")).key(self.key("claimed")).key(self.key("payload"))
+            .key(self.key("attempts")).key(self.key("interval"))
+            .arg(job.id).arg(now_millis()).invoke(conn)?;
+        Ok(done == 1)
+    }
+
+    pub fn fail(&self, conn: &mut RedisConnection, job: &Job) -> RedisResult<JobFailure> {
+        self.fail_job(conn, job.id, self.max_attempts)
+    }
+
+    fn fail_job(&self, conn: &mut RedisConnection, id: u64, max_attempts: u32) -> RedisResult<JobFailure> {
+        let state: i64 = ScriptRegistry::instance().invocation(FAIL_SCRIPT_NAME)
+            .key(self.key("delayed")).key(self.key("claimed")).key(self.key("payload"))
+            .key(self.key("attempts")).key(self.key("interval")).key(self.key("dead"))
+            .arg(id).arg(now_millis()).arg(max_attempts).arg(self.backoff_ms).invoke(conn)?;
+        Ok(match state {
+            1 => JobFailure::Retried,
+            2 => JobFailure::Dead,
+            _ => JobFailure::NotClaimed,
+        })
+    }
+
+    /// jobs that ran out of attempts, newest first. entries whose payload does not decode are left out
+    pub fn dead_jobs(&self, conn: &mut RedisConnection, start: isize, stop: isize) -> RedisResult<Vec<Job>> {
+        let entries: Vec<Vec<u8>> = redis::cmd("LRANGE").arg(self.key("dead")).arg(start).arg(stop).query(&mut conn.conn)?;
+        let mut jobs = vec![];
+        for entry in entries {
+            let split = unwrap_or!(entry.iter().position(|b| *b == b':'), continue);
+            let id = unwrap_or!(u64::decode(&entry[..split]), continue);
+            let mut payload = VarList::new();
+            if !codec::decode_var_list(&entry[split + 1..], &mut payload) {
+                continue;
+            }
+            jobs.push(Job { id, payload, attempts: self.max_attempts });
+        }
+        Ok(jobs)
+    }
+
+    /// drop a pending or recurring job
+    pub fn cancel(&self, conn: &mut RedisConnection, id: u64) -> RedisResult<bool> {
+        let mut pipe = RedisPipeline::new();
+        pipe.atomic();
+        for (part, name) in &[("delayed", "ZREM"), ("claimed", "ZREM"), ("payload", "HDEL"), ("attempts", "HDEL"), ("interval", "HDEL")] {
+            let key = self.key(part);
+            pipe.add(&key, redis::cmd(name).arg(&*key).arg(id).clone());
+        }
+        let values = pipe.execute(conn)?.unwrap_or_default();
+        let mut removed = 0;
+        for value in values.iter().take(2) {
+            removed += redis::from_redis_value::<u64>(value).unwrap_or(0);
+        }
+        Ok(removed > 0)
+    }
+}
@@ -0,0 +1,318 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use redis::{Client, Connection, RedisResult, Value};
+use super::RedisPool;
+use super::codec::RedisCodec;
+
+static INVALIDATE_CHANNEL: &'static str = "__redis__:invalidate";
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LocalCacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    pub invalidations: u64,
+    pub size: usize,
+}
+
+#[derive(Default)]
+struct LocalCacheEntries {
+    values: HashMap<String, (Vec<u8>, u64)>,
+    /// use tick to key, the first entry is the least recently used
+    order: BTreeMap<u64, String>,
+    tick: u64,
+    /// bumped by every invalidation, a read that saw another generation before its GET does not insert
+    generation: u64,
+}
+
+impl LocalCacheEntries {
+    fn get(&mut self, key: &str) -> Option<Vec<u8>> {
+        self.tick += 1;
+        let tick = self.tick;
+        let entry = self.values.get_mut(key)?;
+        self.order.remove(&entry.1);
+        self.order.insert(tick, key.to_string());
+        entry.1 = tick;
+        Some(entry.0.clone())
+    }
+
+    /// insert and return how many entries were evicted to stay under `capacity`
+    fn insert(&mut self, key: &str, value: Vec<u8>, capacity: usize) -> u64 {
+        self.remove(key);
+        let mut evicted = 0;
+        while self.values.len() >= capacity {
+            let (tick, old_key) = match self.order.iter().next() {
+                Some((tick, old_key)) => (*tick, old_key.clone()),
+                None => break,
+            };
+            self.order.remove(&tick);
+            self.values.remove(&old_key);
+            evicted += 1;
+        }
+        self.tick += 1;
+        self.order.insert(self.tick, key.to_string());
+        self.values.insert(key.to_string(), (value, self.tick));
+        evicted
+    }
+
+    fn remove(&mut self, key: &str) -> bool {
+        match self.values.remove(key) {
+            Some((_, tick)) => {
+                self.order.remove(&tick);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// drop `keys`, returns how many were cached
+    fn invalidate(&mut self, keys: &[String]) -> u64 {
+        self.generation += 1;
+        keys.iter().filter(|key| self.remove(key)).count() as u64
+    }
+
+    fn clear(&mut self) {
+        self.generation += 1;
+        self.values.clear();
+        self.order.clear();
+    }
+}
+
+/// opt-in in-process cache of hot keys kept coherent by redis `CLIENT TRACKING`.
+/// invalidations arrive on `__redis__:invalidate` through a listener connection per node
+/// (RESP2 redirect mode). in broadcast mode every key under the tracked prefixes is
+/// invalidated and reads go through `RedisPool`, otherwise reads go through a dedicated
+/// tracked connection of a single node and only keys read there are invalidated
+pub struct LocalCache {
+    entries: Mutex<LocalCacheEntries>,
+    pub capacity: usize,
+    data_conn: Option<Mutex<Connection>>,
+    /// broadcast prefixes, redis sends no invalidation for other keys. empty caches every key
+    prefixes: Vec<String>,
+    /// set once a listener is gone, entries could go stale so nothing is cached any more
+    detached: AtomicBool,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    evictions: AtomicU64,
+    invalidations: AtomicU64,
+    pub stop: Arc<AtomicBool>,
+}
+
+impl LocalCache {
+    fn new(capacity: usize, data_conn: Option<Connection>, prefixes: Vec<String>) -> LocalCache {
+        LocalCache {
+            entries: Mutex::new(LocalCacheEntries::default()),
+            capacity: ::std::cmp::max(capacity, 1),
+            data_conn: data_conn.map(Mutex::new),
+            prefixes,
+            detached: AtomicBool::new(false),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            evictions: AtomicU64::new(0),
+            invalidations: AtomicU64::new(0),
+            stop: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// broadcast mode over every node in `urls` (each master in cluster mode),
+    /// caching only keys starting with one of `prefixes`
+    pub fn open_broadcast(urls: Vec<String>, prefixes: Vec<String>, capacity: usize) -> RedisResult<(Arc<LocalCache>, Vec<JoinHandle<()>>)> {
+        let cache = Arc::new(LocalCache::new(capacity, None, prefixes.clone()));
+        let mut handles = vec![];
+        for url in urls {
+            let mut listener = Client::open(&*url)?.get_connection()?;
+            let id: i64 = redis::cmd("CLIENT").arg("ID").query(&mut listener)?;
+            let mut cmd = redis::cmd("CLIENT");
+            cmd.arg("TRACKING").arg("on").arg("REDIRECT").arg(id).arg("BCAST");
+            for prefix in &prefixes {
+                cmd.arg("PREFIX").arg(&**prefix);
+            }
+            cmd.query::<()>(&mut listener)?;
+            handles.push(LocalCache::spawn_listener(cache.clone(), listener)?);
+        }
+        Ok((cache, handles))
+    }
+
+    /// default tracking mode on the single node at `url`
+    pub fn open_tracking(url: &str, capacity: usize) -> RedisResult<(Arc<LocalCache>, JoinHandle<()>)> {
+        let client = Client::open(url)?;
+        let mut listener = client.get_connection()?;
+        let id: i64 = redis::cmd("CLIENT").arg("ID").query(&mut listener)?;
+        let mut data_conn = client.get_connection()?;
+        redis::cmd("CLIENT").arg("TRACKING").arg("on").arg("REDIRECT").arg(id).query::<()>(&mut data_conn)?;
+        let cache = Arc::new(LocalCache::new(capacity, Some(data_conn), vec![]));
+        let handle = LocalCache::spawn_listener(cache.clone(), listener)?;
+        Ok((cache, handle))
+    }
+
+    fn spawn_listener(cache: Arc<LocalCache>, mut listener: Connection) -> RedisResult<JoinHandle<()>> {
+        listener.set_read_timeout(Some(Duration::from_secs(1)))?;
+        Ok(thread::spawn(move || {
+            let mut pubsub = listener.as_pubsub();
+            if pubsub.subscribe(INVALIDATE_CHANNEL).is_err() {
+                cache.detach();
+                return;
+            }
+            while !cache.stop.load(Ordering::SeqCst) {
+                let msg = match pubsub.get_message() {
+                    Ok(msg) => msg,
+                    Err(ref err) if err.is_timeout() => continue,
+                    Err(_) => break,
+                };
+                match msg.get_payload::<Value>() {
+                    // nil is sent on FLUSHALL / FLUSHDB
+                    Ok(Value::Nil) => cache.clear(),
+                    Ok(value) => {
+                        let keys: Vec<String> = redis::from_redis_value(&value).unwrap_or_default();
+                        cache.invalidate(&keys);
+                    }
+                    Err(_) => cache.clear(),
+                }
+            }
+            cache.detach();
+        }))
+    }
+
+    /// without the listener nothing keeps the entries coherent, stop caching for good
+    fn detach(&self) {
+        self.detached.store(true, Ordering::SeqCst);
+        self.clear();
+    }
+
+    pub fn is_detached(&self) -> bool {
+        self.detached.load(Ordering::SeqCst)
+    }
+
+    fn cacheable(&self, key: &str) -> bool {
+        self.prefixes.is_empty() || self.prefixes.iter().any(|prefix| key.starts_with(&**prefix))
+    }
+
+    fn invalidate(&self, keys: &[String]) {
+        let removed = self.entries.lock().unwrap().invalidate(keys);
+        self.invalidations.fetch_add(removed, Ordering::Relaxed);
+    }
+
+    pub fn clear(&self) {
+        self.entries.lock().unwrap().clear();
+    }
+
+    /// raw value of `key`, from the local cache when present. keys outside the tracked
+    /// prefixes are read from redis every time
+    pub fn get(&self, key: &str) -> RedisResult<Option<Vec<u8>>> {
+        let generation = {
+            let mut entries = self.entries.lock().unwrap();
+            if let Some(value) = entries.get(key) {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                return Ok(Some(value));
+            }
+            entries.generation
+        };
+        self.misses.fetch_add(1, Ordering::Relaxed);
+
+        let value: Option<Vec<u8>> = match self.data_conn {
+            Some(ref conn) => redis::cmd("GET").arg(key).query(&mut *conn.lock().unwrap())?,
+            None => {
+                let pool = RedisPool::instance();
+                let mut conn = unwrap_or!(pool.get_redis_connection(),
+                                          fail!((redis::ErrorKind::IoError, "no redis connection")));
+                let value = redis::cmd("GET").arg(key).query(&mut conn.conn);
+                pool.release_redis_connection(conn);
+                value?
+            }
+        };
+        if let Some(ref value) = value {
+            if self.cacheable(key) {
+                // checked under the lock, `detach` sets the flag before it clears. an invalidation
+                // that came in since the GET may be for the value just read
+                let mut entries = self.entries.lock().unwrap();
+                if !self.is_detached() && entries.generation == generation {
+                    let evicted = entries.insert(key, value.clone(), self.capacity);
+                    self.evictions.fetch_add(evicted, Ordering::Relaxed);
+                }
+            }
+        }
+        Ok(value)
+    }
+
+    pub fn get_value<T: RedisCodec>(&self, key: &str) -> RedisResult<Option<T>> {
+        match self.get(key)? {
+            Some(data) => Ok(Some(unwrap_or!(T::decode(&data),
+                                             fail!((redis::ErrorKind::TypeError, "redis value can not decode"))))),
+            None => Ok(None),
+        }
+    }
+
+    pub fn stats(&self) -> LocalCacheStats {
+        LocalCacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            evictions: self.evictions.load(Ordering::Relaxed),
+            invalidations: self.invalidations.load(Ordering::Relaxed),
+            size: self.entries.lock().unwrap().values.len(),
+        }
+    }
+
+    pub fn close(&self) {
+        self.stop.store(true, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::LocalCacheEntries;
+
+    fn keys(entries: &LocalCacheEntries) -> Vec<String> {
+        entries.order.values().cloned().collect()
+    }
+
+    #[test]
+    fn lru_order() {
+        let mut entries = LocalCacheEntries::default();
+        entries.insert("a", vec![1], 10);
+        entries.insert("b", vec![2], 10);
+        entries.insert("c", vec![3], 10);
+        assert_eq!(entries.get("a"), Some(vec![1]));
+        assert_eq!(keys(&entries), vec!["b", "c", "a"]);
+        entries.insert("b", vec![4], 10);
+        assert_eq!(keys(&entries), vec!["c", "a", "b"]);
+        assert_eq!(entries.get("b"), Some(vec![4]));
+        assert_eq!(entries.get("d"), None);
+    }
+
+    #[test]
+    fn capacity_evicts_least_recent() {
+        let mut entries = LocalCacheEntries::default();
+        assert_eq!(entries.insert("a", vec![1], 2), 0);
+        assert_eq!(entries.insert("b", vec![2], 2), 0);
+        entries.get("a");
+        assert_eq!(entries.insert("c", vec![3], 2), 1);
+        assert_eq!(entries.get("b"), None);
+        assert_eq!(keys(&entries), vec!["a", "c"]);
+        assert_eq!(entries.values.len(), 2);
+    }
+
+    #[test]
+    fn invalidate_and_generation() {
+        let mut entries = LocalCacheEntries::default();
+        entries.insert("a", vec![1], 10);
+        entries.insert("b", vec![2], 10);
+        let generation = entries.generation;
+        assert_eq!(entries.invalidate(&["a".to_string(), "x".to_string()]), 1);
+        assert_ne!(entries.generation, generation);
+        assert_eq!(entries.get("a"), None);
+        assert_eq!(keys(&entries), vec!["b"]);
+
+        // a message for keys that are not cached still marks reads in flight as stale
+        let generation = entries.generation;
+        assert_eq!(entries.invalidate(&["y".to_string()]), 0);
+        assert_ne!(entries.generation, generation);
+
+        let generation = entries.generation;
+        entries.clear();
+        assert_ne!(entries.generation, generation);
+        assert!(entries.values.is_empty() && entries.order.is_empty());
+    }
+}
@@ -0,0 +1,168 @@
+use std::sync::Once;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use std::thread;
+use redis::RedisResult;
+use super::{RedisConnection, RedisPool, ScriptRegistry};
+
+static RELEASE_SCRIPT_NAME: &'static str = "rua_lock_release";
+static EXTEND_SCRIPT_NAME: &'static str = "rua_lock_extend";
+
+static RELEASE_SCRIPT: &'static str = r"
+if redis.call('GET', KEYS[1]) == ARGV[1] then
+    return redis.call('DEL', KEYS[1])
+end
+return 0";
+
+static EXTEND_SCRIPT: &'static str = r"
+if redis.call('GET', KEYS[1]) == ARGV[1] then
+    return redis.call('PEXPIRE', KEYS[1], ARGV[2])
+end
+return 0";
+
+/// sleep between two SET NX attempts while waiting for a lock
+static RETRY_INTERVAL_MS: u64 = 20;
+
+static REGISTER: Once = Once::new();
+static TOKEN_SEQ: AtomicUsize = AtomicUsize::new(0);
+
+fn register_scripts() {
+    REGISTER.call_once(|| {
+        let registry = ScriptRegistry::instance();
+        registry.register(RELEASE_SCRIPT_NAME, RELEASE_SCRIPT);
+        registry.register(EXTEND_SCRIPT_NAME, EXTEND_SCRIPT);
+    });
+}
+
+fn new_token() -> String {
+    let now = time::OffsetDateTime::now_utc() - time::OffsetDateTime::unix_epoch();
+    format!("{}:{}:{}", std::process::id(), now.whole_nanoseconds(), TOKEN_SEQ.fetch_add(1, Ordering::SeqCst))
+}
+
+/// a held lock, `token` identifies the owner so only it can release or extend
+pub struct RedisLock {
+    pub key: String,
+    pub token: String,
+    pub ttl: Duration,
+}
+
+impl RedisLock {
+    /// one SET NX PX attempt
+    pub fn try_lock(conn: &mut RedisConnection, key: &str, ttl: Duration) -> RedisResult<Option<RedisLock>> {
+        check_ttl(ttl)?;
+        let token = new_token();
+        let result: Option<String> = redis::cmd("SET").arg(key).arg(&*token).arg("NX")
+            .arg("PX").arg(ttl.as_millis() as u64).query(&mut conn.conn)?;
+        match result {
+            Some(_) => Ok(Some(RedisLock {
+                key: key.to_string(),
+                token,
+                ttl,
+            })),
+            None => Ok(None),
+        }
+    }
+
+    /// retry `try_lock` until it succeeds or `wait` elapses
+    pub fn lock(conn: &mut RedisConnection, key: &str, ttl: Duration, wait: Duration) -> RedisResult<Option<RedisLock>> {
+        let deadline = Instant::now() + wait;
+        loop {
+            if let Some(lock) = RedisLock::try_lock(conn, key, ttl)? {
+                return Ok(Some(lock));
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Ok(None);
+            }
+            thread::sleep(::std::cmp::min(Duration::from_millis(RETRY_INTERVAL_MS), deadline - now));
+        }
+    }
+
+    /// delete the key if we still own it, false when the lock already expired or was taken over
+    pub fn release(&self, conn: &mut RedisConnection) -> RedisResult<bool> {
+        register_scripts();
+        let deleted: i64 = ScriptRegistry::instance().invocation(RELEASE_SCRIPT_NAME)
+            .key(&*self.key).arg(&*self.token).invoke(conn)?;
+        Ok(deleted == 1)
+    }
+
+    /// reset the ttl if we still own the lock
+    pub fn extend(&mut self, conn: &mut RedisConnection, ttl: Duration) -> RedisResult<bool> {
+        check_ttl(ttl)?;
+        register_scripts();
+        let extended: i64 = ScriptRegistry::instance().invocation(EXTEND_SCRIPT_NAME)
+            .key(&*self.key).arg(&*self.token).arg(ttl.as_millis() as u64).invoke(conn)?;
+        if extended == 1 {
+            self.ttl = ttl;
+        }
+        Ok(extended == 1)
+    }
+}
+
+/// PX 0 is an error reply, a ttl under a millisecond would send it
+fn check_ttl(ttl: Duration) -> RedisResult<()> {
+    if ttl.as_millis() == 0 {
+        fail!((redis::ErrorKind::ClientError, "lock ttl must be at least 1ms"));
+    }
+    Ok(())
+}
+
+/// back into the pool unless `result` broke the connection
+fn give_back<T>(pool: &mut RedisPool, conn: RedisConnection, result: &RedisResult<T>) {
+    match *result {
+        Err(ref err) if err.is_io_error() || err.is_connection_dropped() => pool.discard_redis_connection(conn),
+        _ => pool.release_redis_connection(conn),
+    }
+}
+
+/// releases the lock through a pooled connection when dropped
+pub struct RedisLockGuard {
+    lock: Option<RedisLock>,
+}
+
+impl RedisLockGuard {
+    pub fn lock(key: &str, ttl: Duration, wait: Duration) -> RedisResult<Option<RedisLockGuard>> {
+        let pool = RedisPool::instance();
+        let mut conn = unwrap_or!(pool.get_redis_connection(),
+                                  fail!((redis::ErrorKind::IoError, "no redis connection")));
+        let result = RedisLock::lock(&mut conn, key, ttl, wait);
+        give_back(pool, conn, &result);
+        Ok(result?.map(|lock| RedisLockGuard { lock: Some(lock) }))
+    }
+
+    pub fn get_lock(&self) -> &RedisLock {
+        self.lock.as_ref().unwrap()
+    }
+
+    pub fn extend(&mut self, ttl: Duration) -> RedisResult<bool> {
+        let pool = RedisPool::instance();
+        let mut conn = unwrap_or!(pool.get_redis_connection(),
+                                  fail!((redis::ErrorKind::IoError, "no redis connection")));
+        let result = self.lock.as_mut().unwrap().extend(&mut conn, ttl);
+        give_back(pool, conn, &result);
+        result
+    }
+
+    /// release now instead of at drop, reporting whether we still owned the lock
+    pub fn unlock(mut self) -> RedisResult<bool> {
+        let lock = self.lock.take().unwrap();
+        release_by_pool(&lock)
+    }
+}
+
+fn release_by_pool(lock: &RedisLock) -> RedisResult<bool> {
+    let pool = RedisPool::instance();
+    let mut conn = unwrap_or!(pool.get_redis_connection(),
+                              fail!((redis::ErrorKind::IoError, "no redis connection")));
+    let result = lock.release(&mut conn);
+    give_back(pool, conn, &result);
+    result
+}
+
+impl Drop for RedisLockGuard {
+    fn drop(&mut self) {
+        if let Some(lock) = self.lock.take() {
+            let _ = release_by_pool(&lock);
+        }
+    }
+}
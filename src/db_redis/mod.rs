@@ -1,27 +1,163 @@
-use std::sync::{Mutex, Arc};
-use redis::{ConnectionInfo, Msg, Client, ConnectionLike};
-use redis::cluster::ClusterClient;
+use std::sync::{Mutex, Arc, Condvar};
+use redis::{ConnectionInfo, Msg, Client, ConnectionLike, Connection, RedisResult, Value};
+use redis::cluster::{ClusterClient, ClusterConnection};
 use std::sync::mpsc::Receiver;
 use std::str::FromStr;
 use redis::aio::PubSub;
 use std::net::TcpStream;
+use std::time::{Duration, Instant};
+
+pub mod codec;
+pub mod cluster;
+pub mod pipeline;
+pub mod script;
+pub mod lock;
+pub mod stream;
+pub mod rate_limit;
+pub mod session;
+pub mod scan;
+pub mod job_queue;
+pub mod local_cache;
+mod commands;
+
+pub use self::codec::RedisCodec;
+pub use self::pipeline::RedisPipeline;
+pub use self::script::ScriptRegistry;
+pub use self::lock::{RedisLock, RedisLockGuard};
+pub use self::stream::{StreamConsumer, StreamEntry};
+pub use self::rate_limit::RateLimitResult;
+pub use self::session::{Session, SessionStore};
+pub use self::scan::{KeyScanner, HashScanner, SortedSetScanner, BulkResult, BulkThrottle};
+pub use self::job_queue::{Job, JobFailure, JobQueue};
+pub use self::local_cache::{LocalCache, LocalCacheStats};
 
 static REDIS_SUB_POOL_NAME: &'static str = "redis_sub";
 
+#[derive(Debug, Clone)]
+pub struct RedisPoolConfig {
+    /// max connections open at the same time, idle and in use together
+    pub max_size: usize,
+    /// how long `get_redis_connection` waits for a free connection
+    pub wait_timeout: Duration,
+    /// idle connections unused for longer than this are closed
+    pub idle_timeout: Duration,
+    /// ping idle connections before handing them out
+    pub test_on_checkout: bool,
+    /// open `ClusterClient` connections, otherwise a plain connection to the first url
+    pub cluster_mode: bool,
+}
+
+impl Default for RedisPoolConfig {
+    fn default() -> RedisPoolConfig {
+        RedisPoolConfig {
+            max_size: 16,
+            wait_timeout: Duration::from_secs(3),
+            idle_timeout: Duration::from_secs(300),
+            test_on_checkout: true,
+            cluster_mode: true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RedisPoolMetrics {
+    pub created: u64,
+    pub evicted: u64,
+    pub in_use: usize,
+    pub idle: usize,
+    pub waiting: usize,
+}
+
+pub enum RedisConn {
+    Single(Connection),
+    Cluster(ClusterConnection),
+}
+
+impl ConnectionLike for RedisConn {
+    fn req_packed_command(&mut self, cmd: &[u8]) -> RedisResult<Value> {
+        match self {
+            RedisConn::Single(conn) => conn.req_packed_command(cmd),
+            RedisConn::Cluster(conn) => conn.req_packed_command(cmd),
+        }
+    }
+
+    fn req_packed_commands(&mut self, cmd: &[u8], offset: usize, count: usize) -> RedisResult<Vec<Value>> {
+        match self {
+            RedisConn::Single(conn) => conn.req_packed_commands(cmd, offset, count),
+            RedisConn::Cluster(conn) => conn.req_packed_commands(cmd, offset, count),
+        }
+    }
+
+    fn get_db(&self) -> i64 {
+        match self {
+            RedisConn::Single(conn) => conn.get_db(),
+            RedisConn::Cluster(conn) => conn.get_db(),
+        }
+    }
+
+    fn check_connection(&mut self) -> bool {
+        match self {
+            RedisConn::Single(conn) => conn.check_connection(),
+            RedisConn::Cluster(conn) => conn.check_connection(),
+        }
+    }
+
+    fn is_open(&self) -> bool {
+        match self {
+            RedisConn::Single(conn) => conn.is_open(),
+            RedisConn::Cluster(conn) => conn.is_open(),
+        }
+    }
+}
+
+pub struct RedisConnection {
+    pub conn: RedisConn,
+    /// first configured url, used as template when a cluster node must be reached directly
+    pub seed_url: String,
+    pub last_use_time: f64,
+    /// single node connections for WATCH / MULTI in cluster mode
+    pub nodes: cluster::NodeConnections,
+}
+
+impl RedisConnection {
+    pub fn is_cluster(&self) -> bool {
+        match self.conn {
+            RedisConn::Cluster(_) => true,
+            RedisConn::Single(_) => false,
+        }
+    }
+}
+
 pub struct RedisPool {
-    pub db_redis: Vec<ClusterClient>,
+    pub db_redis: Vec<RedisConnection>,
     pub url_list: Vec<String>,
+    pub config: RedisPoolConfig,
+    pub created: u64,
+    pub evicted: u64,
+    pub in_use: usize,
+    pub waiting: usize,
     pub mutex: Mutex<i32>,
+    condvar: Condvar,
 }
 
 static mut el: *mut RedisPool = 0 as *mut _;
 
+fn now_seconds() -> f64 {
+    (time::OffsetDateTime::now_utc() - time::OffsetDateTime::unix_epoch()).as_seconds_f64()
+}
+
 impl RedisPool {
     pub fn new() -> RedisPool {
         RedisPool {
             db_redis: Vec::new(),
             url_list: Vec::new(),
+            config: RedisPoolConfig::default(),
+            created: 0,
+            evicted: 0,
+            in_use: 0,
+            waiting: 0,
             mutex: Mutex::new(0),
+            condvar: Condvar::new(),
         }
     }
 
@@ -35,26 +171,119 @@ impl RedisPool {
         }
     }
 
-    fn init_connection(&self) -> Option<ClusterClient> {
-        let mut cluster = ok_or!(ClusterClient::open(self.url_list.clone()), return None);
-        Some(cluster)
+    fn init_connection(url_list: Vec<String>, cluster_mode: bool) -> Option<RedisConnection> {
+        let seed_url = unwrap_or!(url_list.first(), return None).clone();
+        let conn = if cluster_mode {
+            let cluster = ok_or!(ClusterClient::open(url_list), return None);
+            RedisConn::Cluster(ok_or!(cluster.get_connection(), return None))
+        } else {
+            let client = ok_or!(Client::open(&*seed_url), return None);
+            RedisConn::Single(ok_or!(client.get_connection(), return None))
+        };
+        Some(RedisConnection {
+            conn,
+            seed_url,
+            last_use_time: now_seconds(),
+            nodes: cluster::NodeConnections::new(),
+        })
     }
 
-    fn set_url_list(&mut self, url_list: Vec<String>) -> bool {
+    pub fn set_url_list(&mut self, url_list: Vec<String>) -> bool {
+        let _guard = self.mutex.lock().unwrap();
         self.url_list = url_list;
         true
     }
 
-    pub fn get_redis_connection(&mut self) -> Option<ClusterClient> {
+    pub fn set_config(&mut self, config: RedisPoolConfig) {
         let _guard = self.mutex.lock().unwrap();
-        if self.db_redis.is_empty() {
-            return self.init_connection();
+        self.config = config;
+        self.condvar.notify_all();
+    }
+
+    pub fn metrics(&self) -> RedisPoolMetrics {
+        let _guard = self.mutex.lock().unwrap();
+        RedisPoolMetrics {
+            created: self.created,
+            evicted: self.evicted,
+            in_use: self.in_use,
+            idle: self.db_redis.len(),
+            waiting: self.waiting,
         }
-        self.db_redis.pop()
     }
 
-    pub fn release_redis_connection(&mut self, client: ClusterClient) {
+    /// hand out an idle connection, open a new one while under `max_size`,
+    /// or wait up to `wait_timeout` for another thread to release one
+    pub fn get_redis_connection(&mut self) -> Option<RedisConnection> {
+        let mut guard = self.mutex.lock().unwrap();
+        let deadline = Instant::now() + self.config.wait_timeout;
+        loop {
+            let now = now_seconds();
+            let idle_timeout = self.config.idle_timeout.as_secs_f64();
+            let before = self.db_redis.len();
+            self.db_redis.retain(|c| now - c.last_use_time < idle_timeout);
+            self.evicted += (before - self.db_redis.len()) as u64;
+
+            while let Some(mut conn) = self.db_redis.pop() {
+                if self.config.test_on_checkout && !conn.conn.check_connection() {
+                    self.evicted += 1;
+                    continue;
+                }
+                conn.last_use_time = now;
+                self.in_use += 1;
+                return Some(conn);
+            }
+
+            if self.in_use < self.config.max_size {
+                // reserve the slot and connect without the lock, a slow node must not block
+                // every other checkout and release
+                self.in_use += 1;
+                let url_list = self.url_list.clone();
+                let cluster_mode = self.config.cluster_mode;
+                drop(guard);
+                let conn = RedisPool::init_connection(url_list, cluster_mode);
+                let _guard = self.mutex.lock().unwrap();
+                match conn {
+                    Some(conn) => {
+                        self.created += 1;
+                        return Some(conn);
+                    }
+                    None => {
+                        self.in_use = self.in_use.saturating_sub(1);
+                        self.condvar.notify_one();
+                        return None;
+                    }
+                }
+            }
+
+            let wait_now = Instant::now();
+            if wait_now >= deadline {
+                return None;
+            }
+            self.waiting += 1;
+            let (next, _) = self.condvar.wait_timeout(guard, deadline - wait_now).unwrap();
+            guard = next;
+            self.waiting -= 1;
+        }
+    }
+
+    pub fn release_redis_connection(&mut self, mut client: RedisConnection) {
+        let _guard = self.mutex.lock().unwrap();
+        self.in_use = self.in_use.saturating_sub(1);
+        if client.conn.is_open() && self.db_redis.len() + self.in_use < self.config.max_size {
+            client.last_use_time = now_seconds();
+            self.db_redis.push(client);
+        } else {
+            self.evicted += 1;
+        }
+        self.condvar.notify_one();
+    }
+
+    /// give back the slot of a connection that hit an io error without pooling it
+    pub fn discard_redis_connection(&mut self, client: RedisConnection) {
         let _guard = self.mutex.lock().unwrap();
-        self.db_redis.push(client);
+        drop(client);
+        self.in_use = self.in_use.saturating_sub(1);
+        self.evicted += 1;
+        self.condvar.notify_one();
     }
-}
\ No newline at end of file
+}
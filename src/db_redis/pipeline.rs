@@ -0,0 +1,183 @@
+use std::collections::HashMap;
+use redis::{Cmd, FromRedisValue, RedisResult, Value};
+use super::{RedisConn, RedisConnection};
+use super::cluster;
+
+/// queued commands sent to redis in one round trip.
+/// every command carries the key it is routed by, in cluster mode the pipeline is
+/// split per hash slot and the replies are put back in queue order
+pub struct RedisPipeline {
+    commands: Vec<(Option<String>, Cmd)>,
+    atomic: bool,
+}
+
+impl RedisPipeline {
+    pub fn new() -> RedisPipeline {
+        RedisPipeline {
+            commands: vec![],
+            atomic: false,
+        }
+    }
+
+    /// wrap the commands in MULTI / EXEC, in cluster mode every key must share one slot
+    pub fn atomic(&mut self) -> &mut RedisPipeline {
+        self.atomic = true;
+        self
+    }
+
+    pub fn add(&mut self, key: &str, cmd: Cmd) -> &mut RedisPipeline {
+        self.commands.push((Some(key.to_string()), cmd));
+        self
+    }
+
+    pub fn add_keyless(&mut self, cmd: Cmd) -> &mut RedisPipeline {
+        self.commands.push((None, cmd));
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.commands.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.commands.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.commands.clear();
+    }
+
+    fn build(&self, indexes: &[usize]) -> redis::Pipeline {
+        let mut pipe = redis::pipe();
+        if self.atomic {
+            pipe.atomic();
+        }
+        for idx in indexes {
+            pipe.add_command(self.commands[*idx].1.clone());
+        }
+        pipe
+    }
+
+    /// the single slot shared by every keyed command, error if they spread over several
+    pub fn single_slot(&self) -> RedisResult<Option<u16>> {
+        let keys: Vec<&str> = self.commands.iter().filter_map(|(key, _)| key.as_ref().map(|k| &**k)).collect();
+        cluster::keys_slot(&keys)
+    }
+
+    /// run the pipeline, `None` means an atomic pipeline was aborted because a watched key changed
+    pub fn execute(&self, conn: &mut RedisConnection) -> RedisResult<Option<Vec<Value>>> {
+        if self.commands.is_empty() {
+            return Ok(Some(vec![]));
+        }
+        let all: Vec<usize> = (0..self.commands.len()).collect();
+        match conn.conn {
+            RedisConn::Single(ref mut single) => self.execute_on(single, &all),
+            RedisConn::Cluster(ref mut cluster_conn) => {
+                if self.atomic {
+                    let slot = self.single_slot()?.unwrap_or(0);
+                    let (url, mut node) = conn.nodes.take(cluster_conn, &conn.seed_url, slot)?;
+                    let result = self.execute_on(&mut node, &all);
+                    conn.nodes.give_back(url, node, result.is_ok());
+                    return result;
+                }
+
+                let mut groups: Vec<(Option<u16>, Vec<usize>)> = vec![];
+                let mut positions: HashMap<u16, usize> = HashMap::new();
+                for (idx, (key, _)) in self.commands.iter().enumerate() {
+                    let slot = unwrap_or!(key.as_ref().map(|k| cluster::key_slot(k)), {
+                        groups.push((None, vec![idx]));
+                        continue;
+                    });
+                    match positions.get(&slot) {
+                        Some(pos) => groups[*pos].1.push(idx),
+                        None => {
+                            positions.insert(slot, groups.len());
+                            groups.push((Some(slot), vec![idx]));
+                        }
+                    }
+                }
+                let mut results = vec![Value::Nil; self.commands.len()];
+                for (_, indexes) in &groups {
+                    self.fill_results(cluster_conn, indexes, &mut results)?;
+                }
+                Ok(Some(results))
+            }
+        }
+    }
+
+    fn fill_results<C: redis::ConnectionLike>(&self, conn: &mut C, indexes: &[usize], results: &mut Vec<Value>) -> RedisResult<()> {
+        let values = unwrap_or!(self.execute_on(conn, indexes)?, return Ok(()));
+        for (idx, value) in indexes.iter().zip(values.into_iter()) {
+            results[*idx] = value;
+        }
+        Ok(())
+    }
+
+    fn execute_on<C: redis::ConnectionLike>(&self, conn: &mut C, indexes: &[usize]) -> RedisResult<Option<Vec<Value>>> {
+        let value: Value = self.build(indexes).query(conn)?;
+        match value {
+            Value::Nil => Ok(None),
+            Value::Bulk(values) => Ok(Some(values)),
+            other => Ok(Some(vec![other])),
+        }
+    }
+
+    pub fn query<T: FromRedisValue>(&self, conn: &mut RedisConnection) -> RedisResult<Option<T>> {
+        match self.execute(conn)? {
+            Some(values) => Ok(Some(T::from_redis_value(&Value::Bulk(values))?)),
+            None => Ok(None),
+        }
+    }
+}
+
+impl RedisConnection {
+    /// optimistic transaction: WATCH `keys`, let `func` read them and queue writes,
+    /// then EXEC; retried up to `max_retries` times while a watched key changes.
+    /// `func` receives the connection the keys are watched on
+    pub fn watch_transaction<T, F>(&mut self, keys: &[&str], max_retries: usize, mut func: F) -> RedisResult<Option<T>>
+        where F: FnMut(&mut RedisConn, &mut RedisPipeline) -> RedisResult<Option<T>>
+    {
+        let mut node = match self.conn {
+            RedisConn::Cluster(ref mut cluster_conn) => {
+                let slot = cluster::keys_slot(keys)?.unwrap_or(0);
+                let (url, node) = self.nodes.take(cluster_conn, &self.seed_url, slot)?;
+                Some((url, RedisConn::Single(node)))
+            }
+            RedisConn::Single(_) => None,
+        };
+        let result = {
+            let conn = match node {
+                Some((_, ref mut node)) => node,
+                None => &mut self.conn,
+            };
+            watch_loop(conn, keys, max_retries, &mut func)
+        };
+        // a failed attempt may leave keys watched, the node connection is not reused then
+        if let Some((url, RedisConn::Single(node))) = node {
+            self.nodes.give_back(url, node, result.is_ok());
+        }
+        result
+    }
+}
+
+fn watch_loop<T, F>(conn: &mut RedisConn, keys: &[&str], max_retries: usize, func: &mut F) -> RedisResult<Option<T>>
+    where F: FnMut(&mut RedisConn, &mut RedisPipeline) -> RedisResult<Option<T>>
+{
+    for _ in 0..max_retries + 1 {
+        redis::cmd("WATCH").arg(keys).query::<()>(conn)?;
+        let mut pipe = RedisPipeline::new();
+        pipe.atomic();
+        let value = match func(conn, &mut pipe)? {
+            Some(value) => value,
+            None => {
+                redis::cmd("UNWATCH").query::<()>(conn)?;
+                return Ok(None);
+            }
+        };
+        let all: Vec<usize> = (0..pipe.len()).collect();
+        if pipe.execute_on(conn, &all)?.is_some() {
+            return Ok(Some(value));
+        }
+    }
+    Ok(None)
+}
@@ -0,0 +1,131 @@
+use std::sync::Once;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use redis::RedisResult;
+use super::{RedisConnection, ScriptRegistry};
+
+static SLIDING_WINDOW_SCRIPT_NAME: &'static str = "rua_rate_sliding_window";
+static TOKEN_BUCKET_SCRIPT_NAME: &'static str = "rua_rate_token_bucket";
+static COUNTER_SCRIPT_NAME: &'static str = "rua_counter_incr";
+
+/// KEYS: zset of request times. ARGV: window ms, limit, unique member.
+/// times come from the redis clock, the callers' clocks may be skewed
+static SLIDING_WINDOW_SCRIPT: &'static str = r"
+redis.replicate_commands()
+local time = redis.call('TIME')
+local now = tonumber(time[1]) * 1000 + math.floor(tonumber(time[2]) / 1000)
+local window = tonumber(ARGV[1])
+local limit = tonumber(ARGV[2])
+redis.call('ZREMRANGEBYSCORE', KEYS[1], '-inf', now - window)
+local count = redis.call('ZCARD', KEYS[1])
+if count < limit then
+    redis.call('ZADD', KEYS[1], now, ARGV[3])
+    redis.call('PEXPIRE', KEYS[1], window)
+    return {1, limit - count - 1, 0}
+end
+local oldest = redis.call('ZRANGE', KEYS[1], 0, 0, 'WITHSCORES')
+local retry = window
+if oldest[2] then
+    retry = tonumber(oldest[2]) + window - now
+end
+return {0, 0, retry}";
+
+/// KEYS: bucket hash. ARGV: capacity, refill tokens per second, cost
+static TOKEN_BUCKET_SCRIPT: &'static str = r"
+redis.replicate_commands()
+local time = redis.call('TIME')
+local now = tonumber(time[1]) * 1000 + math.floor(tonumber(time[2]) / 1000)
+local capacity = tonumber(ARGV[1])
+local rate = tonumber(ARGV[2])
+local cost = tonumber(ARGV[3])
+local bucket = redis.call('HMGET', KEYS[1], 'tokens', 'ts')
+local tokens = tonumber(bucket[1]) or capacity
+local ts = tonumber(bucket[2]) or now
+if now > ts then
+    tokens = math.min(capacity, tokens + (now - ts) * rate / 1000)
+end
+local allowed = 0
+local retry = 0
+if tokens >= cost then
+    tokens = tokens - cost
+    allowed = 1
+else
+    retry = math.ceil((cost - tokens) * 1000 / rate)
+end
+redis.call('HSET', KEYS[1], 'tokens', tokens, 'ts', math.max(now, ts))
+redis.call('PEXPIRE', KEYS[1], math.ceil(capacity * 1000 / rate) * 2)
+return {allowed, math.floor(tokens), retry}";
+
+/// KEYS: counter. ARGV: delta, ttl seconds. the ttl is only set when the counter is created
+static COUNTER_SCRIPT: &'static str = r"
+local value = redis.call('INCRBY', KEYS[1], ARGV[1])
+if redis.call('TTL', KEYS[1]) == -1 then
+    redis.call('EXPIRE', KEYS[1], ARGV[2])
+end
+return value";
+
+static REGISTER: Once = Once::new();
+static MEMBER_SEQ: AtomicUsize = AtomicUsize::new(0);
+
+fn register_scripts() {
+    REGISTER.call_once(|| {
+        let registry = ScriptRegistry::instance();
+        registry.register(SLIDING_WINDOW_SCRIPT_NAME, SLIDING_WINDOW_SCRIPT);
+        registry.register(TOKEN_BUCKET_SCRIPT_NAME, TOKEN_BUCKET_SCRIPT);
+        registry.register(COUNTER_SCRIPT_NAME, COUNTER_SCRIPT);
+    });
+}
+
+fn now_millis() -> u64 {
+    (time::OffsetDateTime::now_utc() - time::OffsetDateTime::unix_epoch()).whole_milliseconds() as u64
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct RateLimitResult {
+    pub allowed: bool,
+    pub remaining: u64,
+    /// ms until the next request can pass, 0 when allowed
+    pub retry_after_ms: u64,
+}
+
+impl RateLimitResult {
+    fn from_reply(reply: Vec<i64>) -> RateLimitResult {
+        RateLimitResult {
+            allowed: reply.get(0).cloned().unwrap_or(0) == 1,
+            remaining: ::std::cmp::max(reply.get(1).cloned().unwrap_or(0), 0) as u64,
+            retry_after_ms: ::std::cmp::max(reply.get(2).cloned().unwrap_or(0), 0) as u64,
+        }
+    }
+}
+
+/// at most `limit` requests in any `window_ms` long window, e.g. per player or per ip key
+pub fn sliding_window(conn: &mut RedisConnection, key: &str, limit: u64, window_ms: u64) -> RedisResult<RateLimitResult> {
+    register_scripts();
+    // only has to be unique, the score is taken from the redis clock
+    let member = format!("{}:{}:{}", now_millis(), std::process::id(), MEMBER_SEQ.fetch_add(1, Ordering::SeqCst));
+    let reply: Vec<i64> = ScriptRegistry::instance().invocation(SLIDING_WINDOW_SCRIPT_NAME)
+        .key(key).arg(window_ms).arg(limit).arg(&*member).invoke(conn)?;
+    Ok(RateLimitResult::from_reply(reply))
+}
+
+/// bucket of `capacity` tokens refilled at `refill_per_sec`, each request takes `cost`
+pub fn token_bucket(conn: &mut RedisConnection, key: &str, capacity: u64, refill_per_sec: f64, cost: u64) -> RedisResult<RateLimitResult> {
+    if !(refill_per_sec > 0.0) || !refill_per_sec.is_finite() {
+        fail!((redis::ErrorKind::ClientError, "refill rate must be positive"));
+    }
+    register_scripts();
+    let reply: Vec<i64> = ScriptRegistry::instance().invocation(TOKEN_BUCKET_SCRIPT_NAME)
+        .key(key).arg(capacity).arg(refill_per_sec).arg(cost).invoke(conn)?;
+    Ok(RateLimitResult::from_reply(reply))
+}
+
+/// add `delta` to a counter expiring `ttl` seconds after its first increment
+pub fn incr_counter(conn: &mut RedisConnection, key: &str, delta: i64, ttl: usize) -> RedisResult<i64> {
+    register_scripts();
+    ScriptRegistry::instance().invocation(COUNTER_SCRIPT_NAME)
+        .key(key).arg(delta).arg(ttl).invoke(conn)
+}
+
+pub fn get_counter(conn: &mut RedisConnection, key: &str) -> RedisResult<i64> {
+    let value: Option<i64> = redis::cmd("GET").arg(key).query(&mut conn.conn)?;
+    Ok(value.unwrap_or(0))
+}
@@ -0,0 +1,382 @@
+use std::collections::VecDeque;
+use std::thread;
+use std::time::Duration;
+use redis::{Client, Connection, RedisResult};
+use super::{RedisConn, RedisConnection};
+use super::cluster;
+
+/// keys asked from redis per SCAN call
+static SCAN_COUNT: usize = 500;
+
+/// cursor based SCAN over every master, never blocks redis like `KEYS *`
+pub struct KeyScanner {
+    nodes: Vec<Connection>,
+    current: usize,
+    cursor: u64,
+    started: bool,
+    buffer: VecDeque<String>,
+    pub pattern: String,
+    pub count: usize,
+    /// only keys of this redis type (string, hash, zset ...), needs redis 6
+    pub key_type: Option<String>,
+}
+
+impl KeyScanner {
+    /// scanner over the node of `conn`, or over every master when `conn` is a cluster connection
+    pub fn new(conn: &mut RedisConnection, pattern: &str, key_type: Option<&str>) -> RedisResult<KeyScanner> {
+        let urls = match conn.conn {
+            RedisConn::Single(_) => vec![conn.seed_url.clone()],
+            RedisConn::Cluster(ref mut cluster_conn) => cluster::master_urls(cluster_conn, &conn.seed_url)?,
+        };
+        let mut nodes = vec![];
+        for url in urls {
+            nodes.push(Client::open(&*url)?.get_connection()?);
+        }
+        Ok(KeyScanner {
+            nodes,
+            current: 0,
+            cursor: 0,
+            started: false,
+            buffer: VecDeque::new(),
+            pattern: pattern.to_string(),
+            count: SCAN_COUNT,
+            key_type: key_type.map(|t| t.to_string()),
+        })
+    }
+
+    /// up to `size` keys, empty once every node is scanned
+    pub fn next_batch(&mut self, size: usize) -> RedisResult<Vec<String>> {
+        let mut batch = vec![];
+        while batch.len() < size {
+            match self.next() {
+                Some(key) => batch.push(key?),
+                None => break,
+            }
+        }
+        Ok(batch)
+    }
+}
+
+impl Iterator for KeyScanner {
+    type Item = RedisResult<String>;
+
+    fn next(&mut self) -> Option<RedisResult<String>> {
+        loop {
+            if let Some(key) = self.buffer.pop_front() {
+                return Some(Ok(key));
+            }
+            if self.current >= self.nodes.len() {
+                return None;
+            }
+            if self.started && self.cursor == 0 {
+                self.current += 1;
+                self.started = false;
+                continue;
+            }
+            let mut cmd = redis::cmd("SCAN");
+            cmd.arg(self.cursor).arg("MATCH").arg(&*self.pattern).arg("COUNT").arg(self.count);
+            if let Some(ref key_type) = self.key_type {
+                cmd.arg("TYPE").arg(&**key_type);
+            }
+            match cmd.query::<(u64, Vec<String>)>(&mut self.nodes[self.current]) {
+                Ok((cursor, keys)) => {
+                    self.cursor = cursor;
+                    self.started = true;
+                    self.buffer.extend(keys);
+                }
+                Err(err) => {
+                    self.current = self.nodes.len();
+                    return Some(Err(err));
+                }
+            }
+        }
+    }
+}
+
+/// HSCAN over the fields of one hash
+pub struct HashScanner<'a> {
+    conn: &'a mut RedisConnection,
+    key: String,
+    pattern: String,
+    cursor: u64,
+    started: bool,
+    buffer: VecDeque<(String, Vec<u8>)>,
+}
+
+impl<'a> HashScanner<'a> {
+    pub fn new(conn: &'a mut RedisConnection, key: &str, pattern: &str) -> HashScanner<'a> {
+        HashScanner {
+            conn,
+            key: key.to_string(),
+            pattern: pattern.to_string(),
+            cursor: 0,
+            started: false,
+            buffer: VecDeque::new(),
+        }
+    }
+}
+
+impl<'a> Iterator for HashScanner<'a> {
+    type Item = RedisResult<(String, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<RedisResult<(String, Vec<u8>)>> {
+        loop {
+            if let Some(item) = self.buffer.pop_front() {
+                return Some(Ok(item));
+            }
+            if self.started && self.cursor == 0 {
+                return None;
+            }
+            let result = redis::cmd("HSCAN").arg(&*self.key).arg(self.cursor).arg("MATCH").arg(&*self.pattern)
+                .arg("COUNT").arg(SCAN_COUNT).query::<(u64, Vec<(String, Vec<u8>)>)>(&mut self.conn.conn);
+            match result {
+                Ok((cursor, items)) => {
+                    self.cursor = cursor;
+                    self.started = true;
+                    self.buffer.extend(items);
+                }
+                Err(err) => {
+                    self.started = true;
+                    self.cursor = 0;
+                    return Some(Err(err));
+                }
+            }
+        }
+    }
+}
+
+/// ZSCAN over the members of one sorted set
+pub struct SortedSetScanner<'a> {
+    conn: &'a mut RedisConnection,
+    key: String,
+    pattern: String,
+    cursor: u64,
+    started: bool,
+    buffer: VecDeque<(String, f64)>,
+}
+
+impl<'a> SortedSetScanner<'a> {
+    pub fn new(conn: &'a mut RedisConnection, key: &str, pattern: &str) -> SortedSetScanner<'a> {
+        SortedSetScanner {
+            conn,
+            key: key.to_string(),
+            pattern: pattern.to_string(),
+            cursor: 0,
+            started: false,
+            buffer: VecDeque::new(),
+        }
+    }
+}
+
+impl<'a> Iterator for SortedSetScanner<'a> {
+    type Item = RedisResult<(String, f64)>;
+
+    fn next(&mut self) -> Option<RedisResult<(String, f64)>> {
+        loop {
+            if let Some(item) = self.buffer.pop_front() {
+                return Some(Ok(item));
+            }
+            if self.started && self.cursor == 0 {
+                return None;
+            }
+            let result = redis::cmd("ZSCAN").arg(&*self.key).arg(self.cursor).arg("MATCH").arg(&*self.pattern)
+                .arg("COUNT").arg(SCAN_COUNT).query::<(u64, Vec<(String, f64)>)>(&mut self.conn.conn);
+            match result {
+                Ok((cursor, items)) => {
+                    self.cursor = cursor;
+                    self.started = true;
+                    self.buffer.extend(items);
+                }
+                Err(err) => {
+                    self.started = true;
+                    self.cursor = 0;
+                    return Some(Err(err));
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BulkResult {
+    pub scanned: u64,
+    pub affected: u64,
+    pub failed: u64,
+}
+
+/// batch size and the pause after every batch, keeps bulk jobs from starving live traffic
+#[derive(Debug, Clone, Copy)]
+pub struct BulkThrottle {
+    pub batch_size: usize,
+    pub pause: Duration,
+}
+
+impl Default for BulkThrottle {
+    fn default() -> BulkThrottle {
+        BulkThrottle {
+            batch_size: 500,
+            pause: Duration::from_millis(10),
+        }
+    }
+}
+
+fn bulk_apply<F>(conn: &mut RedisConnection, pattern: &str, key_type: Option<&str>, throttle: BulkThrottle, mut func: F) -> RedisResult<BulkResult>
+    where F: FnMut(&mut RedisConnection, &[String], &mut BulkResult) -> RedisResult<()>
+{
+    let mut scanner = KeyScanner::new(conn, pattern, key_type)?;
+    let mut result = BulkResult::default();
+    loop {
+        let batch = scanner.next_batch(::std::cmp::max(throttle.batch_size, 1))?;
+        if batch.is_empty() {
+            break;
+        }
+        result.scanned += batch.len() as u64;
+        func(conn, &batch, &mut result)?;
+        thread::sleep(throttle.pause);
+    }
+    Ok(result)
+}
+
+/// delete every key matching `pattern`
+pub fn bulk_delete(conn: &mut RedisConnection, pattern: &str, key_type: Option<&str>, throttle: BulkThrottle) -> RedisResult<BulkResult> {
+    bulk_apply(conn, pattern, key_type, throttle, |conn, batch, result| {
+        let keys: Vec<&str> = batch.iter().map(|k| &**k).collect();
+        result.affected += conn.del_keys(&keys)?;
+        Ok(())
+    })
+}
+
+/// set a `seconds` ttl on every key matching `pattern`
+pub fn bulk_expire(conn: &mut RedisConnection, pattern: &str, key_type: Option<&str>, seconds: usize, throttle: BulkThrottle) -> RedisResult<BulkResult> {
+    bulk_apply(conn, pattern, key_type, throttle, |conn, batch, result| {
+        for key in batch {
+            if conn.expire_key(key, seconds)? {
+                result.affected += 1;
+            }
+        }
+        Ok(())
+    })
+}
+
+/// rename every key matching `pattern` to `rename(key)` with RENAMENX. counted as failed and
+/// left alone: existing new names, new names still matching `pattern` (a later SCAN page would
+/// rename them again) and in cluster mode names that hash to another slot
+pub fn bulk_rename<F>(conn: &mut RedisConnection, pattern: &str, key_type: Option<&str>, throttle: BulkThrottle, rename: F) -> RedisResult<BulkResult>
+    where F: Fn(&str) -> String
+{
+    let is_cluster = conn.is_cluster();
+    bulk_apply(conn, pattern, key_type, throttle, |conn, batch, result| {
+        for key in batch {
+            let new_key = rename(key);
+            if glob_match(pattern, &new_key) || (is_cluster && cluster::key_slot(key) != cluster::key_slot(&new_key)) {
+                result.failed += 1;
+                continue;
+            }
+            match redis::cmd("RENAMENX").arg(&**key).arg(&*new_key).query::<bool>(&mut conn.conn) {
+                Ok(true) => result.affected += 1,
+                _ => result.failed += 1,
+            }
+        }
+        Ok(())
+    })
+}
+
+/// the glob matching of SCAN MATCH: `*`, `?`, `[abc]`, `[^a-z]` and `\` escapes
+pub fn glob_match(pattern: &str, key: &str) -> bool {
+    let (pattern, key) = (pattern.as_bytes(), key.as_bytes());
+    let (mut p, mut k) = (0, 0);
+    // where the last `*` was and the key position it matched up to
+    let mut star = None;
+    while k < key.len() {
+        if p < pattern.len() {
+            let step = match pattern[p] {
+                b'*' => {
+                    star = Some((p, k));
+                    p += 1;
+                    continue;
+                }
+                b'?' => Some(1),
+                b'[' => {
+                    let (matched, len) = class_match(&pattern[p..], key[k]);
+                    if matched { Some(len) } else { None }
+                }
+                b'\\' if p + 1 < pattern.len() => {
+                    if pattern[p + 1] == key[k] { Some(2) } else { None }
+                }
+                c => {
+                    if c == key[k] { Some(1) } else { None }
+                }
+            };
+            if let Some(step) = step {
+                p += step;
+                k += 1;
+                continue;
+            }
+        }
+        match star {
+            Some((star_p, star_k)) => {
+                star = Some((star_p, star_k + 1));
+                p = star_p + 1;
+                k = star_k + 1;
+            }
+            None => return false,
+        }
+    }
+    pattern[p..].iter().all(|c| *c == b'*')
+}
+
+/// `c` against the class at the start of `pattern`, returns whether it matched and the class
+/// length. a class without `]` runs to the end of the pattern like in redis
+fn class_match(pattern: &[u8], c: u8) -> (bool, usize) {
+    let mut i = 1;
+    let negate = pattern.get(1) == Some(&b'^');
+    if negate {
+        i += 1;
+    }
+    let mut matched = false;
+    while i < pattern.len() && pattern[i] != b']' {
+        if pattern[i] == b'\\' && i + 1 < pattern.len() {
+            matched |= pattern[i + 1] == c;
+            i += 2;
+        } else if i + 2 < pattern.len() && pattern[i + 1] == b'-' {
+            let (low, high) = if pattern[i] <= pattern[i + 2] { (pattern[i], pattern[i + 2]) } else { (pattern[i + 2], pattern[i]) };
+            matched |= c >= low && c <= high;
+            i += 3;
+        } else {
+            matched |= pattern[i] == c;
+            i += 1;
+        }
+    }
+    (matched != negate, ::std::cmp::min(i + 1, pattern.len()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::glob_match;
+
+    #[test]
+    fn glob_wildcards() {
+        assert!(glob_match("*", ""));
+        assert!(glob_match("user:*", "user:1"));
+        assert!(!glob_match("user:*", "item:1"));
+        assert!(glob_match("user:*:name", "user:12:name"));
+        assert!(!glob_match("user:*:name", "user:12:age"));
+        assert!(glob_match("h?llo", "hello"));
+        assert!(!glob_match("h?llo", "hllo"));
+        assert!(glob_match("*a*b", "xxaxxb"));
+        assert!(!glob_match("*a*b", "xxaxxbc"));
+    }
+
+    #[test]
+    fn glob_classes_and_escapes() {
+        assert!(glob_match("h[ae]llo", "hallo"));
+        assert!(!glob_match("h[ae]llo", "hillo"));
+        assert!(glob_match("h[^e]llo", "hallo"));
+        assert!(!glob_match("h[^e]llo", "hello"));
+        assert!(glob_match("h[a-c]llo", "hbllo"));
+        assert!(glob_match("h[c-a]llo", "hbllo"));
+        assert!(!glob_match("h[a-c]llo", "hdllo"));
+        assert!(glob_match("a\\*b", "a*b"));
+        assert!(!glob_match("a\\*b", "axb"));
+    }
+}
@@ -0,0 +1,130 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use redis::{Connection, FromRedisValue, RedisResult, ToRedisArgs};
+use super::{RedisConn, RedisConnection};
+use super::cluster;
+
+#[derive(Clone)]
+pub struct RedisScript {
+    pub name: String,
+    pub code: String,
+    pub sha: String,
+}
+
+/// lua scripts registered by name, invoked with EVALSHA and loaded lazily.
+/// a NOSCRIPT reply (fresh node, failover, SCRIPT FLUSH) reloads every script
+/// on every master, then falls back to EVAL
+pub struct ScriptRegistry {
+    pub scripts: HashMap<String, RedisScript>,
+    pub mutex: Mutex<i32>,
+}
+
+static mut el: *mut ScriptRegistry = 0 as *mut _;
+
+impl ScriptRegistry {
+    pub fn new() -> ScriptRegistry {
+        ScriptRegistry {
+            scripts: HashMap::new(),
+            mutex: Mutex::new(0),
+        }
+    }
+
+    pub fn instance() -> &'static mut ScriptRegistry {
+        unsafe {
+            if el == 0 as *mut _ {
+                el = Box::into_raw(Box::new(ScriptRegistry::new()));
+            }
+
+            &mut *el
+        }
+    }
+
+    /// register `code` under `name`, returns the sha1 EVALSHA uses
+    pub fn register(&mut self, name: &str, code: &str) -> String {
+        let _guard = self.mutex.lock().unwrap();
+        let sha = redis::Script::new(code).get_hash().to_string();
+        self.scripts.insert(name.to_string(), RedisScript {
+            name: name.to_string(),
+            code: code.to_string(),
+            sha: sha.clone(),
+        });
+        sha
+    }
+
+    pub fn get_script(&self, name: &str) -> Option<RedisScript> {
+        let _guard = self.mutex.lock().unwrap();
+        self.scripts.get(name).cloned()
+    }
+
+    fn all_scripts(&self) -> Vec<RedisScript> {
+        let _guard = self.mutex.lock().unwrap();
+        self.scripts.values().cloned().collect()
+    }
+
+    /// SCRIPT LOAD every registered script, on each master when `conn` is a cluster connection
+    pub fn load_all(&self, conn: &mut RedisConnection) -> RedisResult<()> {
+        let scripts = self.all_scripts();
+        match conn.conn {
+            RedisConn::Single(ref mut single) => load_scripts(single, &scripts)?,
+            RedisConn::Cluster(ref mut cluster_conn) => {
+                for url in cluster::master_urls(cluster_conn, &conn.seed_url)? {
+                    let mut node = conn.nodes.take_url(&url)?;
+                    let result = load_scripts(&mut node, &scripts);
+                    conn.nodes.give_back(url, node, result.is_ok());
+                    result?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn invocation(&self, name: &str) -> ScriptInvocation {
+        ScriptInvocation {
+            name: name.to_string(),
+            keys: vec![],
+            args: vec![],
+        }
+    }
+}
+
+fn load_scripts(conn: &mut Connection, scripts: &[RedisScript]) -> RedisResult<()> {
+    for script in scripts {
+        redis::cmd("SCRIPT").arg("LOAD").arg(&*script.code).query::<String>(conn)?;
+    }
+    Ok(())
+}
+
+pub struct ScriptInvocation {
+    name: String,
+    keys: Vec<Vec<u8>>,
+    args: Vec<Vec<u8>>,
+}
+
+impl ScriptInvocation {
+    pub fn key<T: ToRedisArgs>(&mut self, key: T) -> &mut ScriptInvocation {
+        self.keys.extend(key.to_redis_args());
+        self
+    }
+
+    pub fn arg<T: ToRedisArgs>(&mut self, arg: T) -> &mut ScriptInvocation {
+        self.args.extend(arg.to_redis_args());
+        self
+    }
+
+    pub fn invoke<T: FromRedisValue>(&self, conn: &mut RedisConnection) -> RedisResult<T> {
+        let registry = ScriptRegistry::instance();
+        let script = unwrap_or!(registry.get_script(&self.name),
+                                fail!((redis::ErrorKind::ClientError, "script not registered")));
+        let result = redis::cmd("EVALSHA").arg(&*script.sha).arg(self.keys.len())
+            .arg(&self.keys).arg(&self.args).query(&mut conn.conn);
+        match result {
+            Err(ref err) if err.kind() == redis::ErrorKind::NoScriptError => {
+                // reload before running, a failed load is reported instead of running every call with EVAL
+                registry.load_all(conn)?;
+                redis::cmd("EVAL").arg(&*script.code).arg(self.keys.len())
+                    .arg(&self.keys).arg(&self.args).query(&mut conn.conn)
+            }
+            other => other,
+        }
+    }
+}
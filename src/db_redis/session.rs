@@ -0,0 +1,212 @@
+use std::fs::File;
+use std::io::Read;
+use std::sync::{Arc, Once};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::{self, JoinHandle};
+use redis::{Client, RedisResult};
+use rua_value_list::VarList;
+use super::{RedisConnection, RedisPipeline, ScriptRegistry};
+use super::codec;
+
+static SESSION_PREFIX: &'static str = "rua_session";
+static SESSION_USER_PREFIX: &'static str = "rua_session_user";
+static TOUCH_SCRIPT_NAME: &'static str = "rua_session_touch";
+
+static FIELD_USER: &'static str = "user";
+static FIELD_PAYLOAD: &'static str = "payload";
+static FIELD_CREATED: &'static str = "created";
+
+/// KEYS: session hash. ARGV: ttl seconds. reads the session and slides its ttl in one step
+static TOUCH_SCRIPT: &'static str = r"
+if redis.call('EXPIRE', KEYS[1], ARGV[1]) == 0 then
+    return false
+end
+return redis.call('HMGET', KEYS[1], 'user', 'payload')";
+
+static REGISTER: Once = Once::new();
+
+fn register_scripts() {
+    REGISTER.call_once(|| {
+        ScriptRegistry::instance().register(TOUCH_SCRIPT_NAME, TOUCH_SCRIPT);
+    });
+}
+
+/// 128 random bits from the os, there is no fallback, a guessable token would hand out sessions
+fn new_token() -> RedisResult<String> {
+    let mut bytes = [0u8; 16];
+    let filled = match File::open("/dev/urandom") {
+        Ok(mut file) => file.read_exact(&mut bytes).is_ok(),
+        Err(_) => false,
+    };
+    if !filled {
+        fail!((redis::ErrorKind::IoError, "read /dev/urandom error"));
+    }
+    Ok(bytes.iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+/// `flags` of `notify-keyspace-events` plus keyevent notifications of expired keys
+fn with_expired_events(flags: &str) -> String {
+    let mut flags = flags.to_string();
+    if !flags.contains('E') {
+        flags.push('E');
+    }
+    if !flags.contains('x') && !flags.contains('A') {
+        flags.push('x');
+    }
+    flags
+}
+
+pub struct Session {
+    pub token: String,
+    pub user: String,
+    pub payload: VarList,
+}
+
+/// login sessions kept in redis hashes keyed by token, each access slides the ttl
+pub struct SessionStore {
+    pub ttl: usize,
+}
+
+impl SessionStore {
+    pub fn new(ttl: usize) -> SessionStore {
+        register_scripts();
+        SessionStore { ttl }
+    }
+
+    pub fn session_key(token: &str) -> String {
+        format!("{}:{}", SESSION_PREFIX, token)
+    }
+
+    fn user_key(user: &str) -> String {
+        format!("{}:{}", SESSION_USER_PREFIX, user)
+    }
+
+    /// token of a new session of `user`
+    pub fn create(&self, conn: &mut RedisConnection, user: &str, payload: &VarList) -> RedisResult<String> {
+        let token = new_token()?;
+        let key = SessionStore::session_key(&token);
+        let user_key = SessionStore::user_key(user);
+        let now = (time::OffsetDateTime::now_utc() - time::OffsetDateTime::unix_epoch()).whole_seconds();
+        let mut pipe = RedisPipeline::new();
+        pipe.add(&key, redis::cmd("HSET").arg(&*key).arg(FIELD_USER).arg(user)
+            .arg(FIELD_PAYLOAD).arg(codec::encode_var_list(payload, 0)).arg(FIELD_CREATED).arg(now).clone());
+        pipe.add(&key, redis::cmd("EXPIRE").arg(&*key).arg(self.ttl).clone());
+        pipe.add(&user_key, redis::cmd("SADD").arg(&*user_key).arg(&*token).clone());
+        pipe.execute(conn)?;
+        Ok(token)
+    }
+
+    /// the session of `token` if still alive, its ttl starts over
+    pub fn get(&self, conn: &mut RedisConnection, token: &str) -> RedisResult<Option<Session>> {
+        let fields: Option<(String, Vec<u8>)> = ScriptRegistry::instance().invocation(TOUCH_SCRIPT_NAME)
+            .key(SessionStore::session_key(token)).arg(self.ttl).invoke(conn)?;
+        let (user, data) = unwrap_or!(fields, return Ok(None));
+        let payload = unwrap_or!(<VarList as codec::RedisCodec>::decode(&data), return Ok(None));
+        Ok(Some(Session {
+            token: token.to_string(),
+            user,
+            payload,
+        }))
+    }
+
+    /// replace the payload and slide the ttl, false if the session is gone
+    pub fn update(&self, conn: &mut RedisConnection, token: &str, payload: &VarList) -> RedisResult<bool> {
+        let key = SessionStore::session_key(token);
+        let exists: bool = redis::cmd("EXPIRE").arg(&*key).arg(self.ttl).query(&mut conn.conn)?;
+        if !exists {
+            return Ok(false);
+        }
+        redis::cmd("HSET").arg(&*key).arg(FIELD_PAYLOAD).arg(codec::encode_var_list(payload, 0))
+            .query::<()>(&mut conn.conn)?;
+        Ok(true)
+    }
+
+    pub fn revoke(&self, conn: &mut RedisConnection, token: &str) -> RedisResult<bool> {
+        let key = SessionStore::session_key(token);
+        let user: Option<String> = redis::cmd("HGET").arg(&*key).arg(FIELD_USER).query(&mut conn.conn)?;
+        let deleted: u64 = redis::cmd("DEL").arg(&*key).query(&mut conn.conn)?;
+        if let Some(user) = user {
+            redis::cmd("SREM").arg(SessionStore::user_key(&user)).arg(token).query::<()>(&mut conn.conn)?;
+        }
+        Ok(deleted > 0)
+    }
+
+    /// revoke every session of `user`, returns how many were still alive
+    pub fn revoke_user(&self, conn: &mut RedisConnection, user: &str) -> RedisResult<u64> {
+        let user_key = SessionStore::user_key(user);
+        let tokens: Vec<String> = redis::cmd("SMEMBERS").arg(&*user_key).query(&mut conn.conn)?;
+        let mut pipe = RedisPipeline::new();
+        for token in &tokens {
+            let key = SessionStore::session_key(token);
+            pipe.add(&key, redis::cmd("DEL").arg(&*key).clone());
+        }
+        pipe.add(&user_key, redis::cmd("DEL").arg(&*user_key).clone());
+        let values = pipe.execute(conn)?.unwrap_or_default();
+        let mut revoked = 0;
+        for value in values.iter().take(tokens.len()) {
+            revoked += redis::from_redis_value::<u64>(value).unwrap_or(0);
+        }
+        Ok(revoked)
+    }
+
+    /// live tokens of `user`, tokens of expired sessions are pruned from the user set
+    pub fn user_sessions(&self, conn: &mut RedisConnection, user: &str) -> RedisResult<Vec<String>> {
+        let user_key = SessionStore::user_key(user);
+        let tokens: Vec<String> = redis::cmd("SMEMBERS").arg(&*user_key).query(&mut conn.conn)?;
+        let mut alive = vec![];
+        for token in tokens {
+            let exists: bool = redis::cmd("EXISTS").arg(SessionStore::session_key(&token)).query(&mut conn.conn)?;
+            if exists {
+                alive.push(token);
+            } else {
+                redis::cmd("SREM").arg(&*user_key).arg(&*token).query::<()>(&mut conn.conn)?;
+            }
+        }
+        Ok(alive)
+    }
+}
+
+/// call `handler` with the token of every session that expires.
+/// keyspace events are local to a node, so pass every master url in cluster mode.
+/// with `enable_events` the listener adds `Ex` to the server's `notify-keyspace-events` itself
+pub fn listen_expired<F>(urls: Vec<String>, enable_events: bool, stop: Arc<AtomicBool>, handler: F) -> RedisResult<Vec<JoinHandle<()>>>
+    where F: Fn(&str) + Send + Sync + 'static
+{
+    let handler = Arc::new(handler);
+    let prefix = format!("{}:", SESSION_PREFIX);
+    let mut handles = vec![];
+    for url in urls {
+        let client = Client::open(&*url)?;
+        let mut conn = client.get_connection()?;
+        if enable_events {
+            let config: Vec<String> = redis::cmd("CONFIG").arg("GET").arg("notify-keyspace-events").query(&mut conn)?;
+            let flags = config.get(1).map(|flags| &**flags).unwrap_or("");
+            let wanted = with_expired_events(flags);
+            if wanted != flags {
+                redis::cmd("CONFIG").arg("SET").arg("notify-keyspace-events").arg(&*wanted).query::<()>(&mut conn)?;
+            }
+        }
+        conn.set_read_timeout(Some(::std::time::Duration::from_secs(1)))?;
+        let handler = handler.clone();
+        let prefix = prefix.clone();
+        let stop = stop.clone();
+        handles.push(thread::spawn(move || {
+            let mut pubsub = conn.as_pubsub();
+            if pubsub.psubscribe("__keyevent@*__:expired").is_err() {
+                return;
+            }
+            while !stop.load(Ordering::SeqCst) {
+                let msg = match pubsub.get_message() {
+                    Ok(msg) => msg,
+                    Err(ref err) if err.is_timeout() => continue,
+                    Err(_) => return,
+                };
+                let key: String = ok_or!(msg.get_payload(), continue);
+                if key.starts_with(&*prefix) {
+                    handler(&key[prefix.len()..]);
+                }
+            }
+        }));
+    }
+    Ok(handles)
+}
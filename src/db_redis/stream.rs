@@ -0,0 +1,172 @@
+use redis::{RedisResult, Value};
+use rua_net_mgr::NetMsg;
+use rua_value_list::VarList;
+use super::RedisConnection;
+use super::codec;
+
+/// field of a stream entry holding the encoded `VarList`
+static STREAM_DATA_FIELD: &'static str = "data";
+
+pub struct StreamEntry {
+    pub id: String,
+    pub data: Vec<u8>,
+}
+
+impl StreamEntry {
+    /// the entry as a `NetMsg` whose var list holds the produced values
+    pub fn to_msg(&self) -> Option<NetMsg> {
+        let mut msg = NetMsg::new();
+        if !codec::decode_var_list(&self.data, msg.get_var_list()) {
+            return None;
+        }
+        Some(msg)
+    }
+}
+
+/// XADD `var_list` to `stream`, trimmed to about `max_len` entries, returns the entry id
+pub fn stream_add(conn: &mut RedisConnection, stream: &str, var_list: &VarList, max_len: Option<usize>) -> RedisResult<String> {
+    let mut cmd = redis::cmd("XADD");
+    cmd.arg(stream);
+    if let Some(max_len) = max_len {
+        cmd.arg("MAXLEN").arg("~").arg(max_len);
+    }
+    cmd.arg("*").arg(STREAM_DATA_FIELD).arg(codec::encode_var_list(var_list, 0));
+    cmd.query(&mut conn.conn)
+}
+
+/// `[[id, [field, value, ...]], ...]`
+fn parse_entries(value: &Value) -> RedisResult<Vec<StreamEntry>> {
+    let mut entries = vec![];
+    let items = match value {
+        Value::Bulk(items) => items,
+        _ => return Ok(entries),
+    };
+    for item in items {
+        let parts = match item {
+            Value::Bulk(parts) if parts.len() >= 2 => parts,
+            _ => continue,
+        };
+        let id: String = redis::from_redis_value(&parts[0])?;
+        let fields = match &parts[1] {
+            Value::Bulk(fields) => fields,
+            // an entry deleted while still pending comes back with nil fields
+            _ => continue,
+        };
+        let mut data = vec![];
+        for pair in fields.chunks(2) {
+            if pair.len() < 2 {
+                continue;
+            }
+            let name: String = redis::from_redis_value(&pair[0])?;
+            if name == STREAM_DATA_FIELD {
+                data = redis::from_redis_value(&pair[1])?;
+            }
+        }
+        entries.push(StreamEntry { id, data });
+    }
+    Ok(entries)
+}
+
+/// one consumer of a consumer group, entries stay pending until `ack`
+/// and are reclaimed from crashed consumers once idle for `min_idle_ms`
+pub struct StreamConsumer {
+    pub stream: String,
+    pub group: String,
+    pub consumer: String,
+    pub count: usize,
+    pub block_ms: usize,
+    pub min_idle_ms: usize,
+    pub claim_cursor: String,
+}
+
+impl StreamConsumer {
+    pub fn new(stream: &str, group: &str, consumer: &str) -> StreamConsumer {
+        StreamConsumer {
+            stream: stream.to_string(),
+            group: group.to_string(),
+            consumer: consumer.to_string(),
+            count: 64,
+            block_ms: 1000,
+            min_idle_ms: 60_000,
+            claim_cursor: "0-0".to_string(),
+        }
+    }
+
+    /// create the group (and the stream) if missing, new groups start at `start_id`
+    pub fn create_group(&self, conn: &mut RedisConnection, start_id: &str) -> RedisResult<()> {
+        let result: RedisResult<()> = redis::cmd("XGROUP").arg("CREATE").arg(&*self.stream)
+            .arg(&*self.group).arg(start_id).arg("MKSTREAM").query(&mut conn.conn);
+        match result {
+            Err(ref err) if err.code() == Some("BUSYGROUP") => Ok(()),
+            other => other,
+        }
+    }
+
+    /// XREADGROUP new entries, waiting up to `block_ms`
+    pub fn read(&self, conn: &mut RedisConnection) -> RedisResult<Vec<StreamEntry>> {
+        let value: Value = redis::cmd("XREADGROUP").arg("GROUP").arg(&*self.group).arg(&*self.consumer)
+            .arg("COUNT").arg(self.count).arg("BLOCK").arg(self.block_ms)
+            .arg("STREAMS").arg(&*self.stream).arg(">").query(&mut conn.conn)?;
+        let streams = match value {
+            Value::Bulk(streams) => streams,
+            _ => return Ok(vec![]),
+        };
+        let mut entries = vec![];
+        for stream in &streams {
+            if let Value::Bulk(parts) = stream {
+                if parts.len() >= 2 {
+                    entries.extend(parse_entries(&parts[1])?);
+                }
+            }
+        }
+        Ok(entries)
+    }
+
+    pub fn ack(&self, conn: &mut RedisConnection, ids: &[String]) -> RedisResult<u64> {
+        if ids.is_empty() {
+            return Ok(0);
+        }
+        redis::cmd("XACK").arg(&*self.stream).arg(&*self.group).arg(ids).query(&mut conn.conn)
+    }
+
+    /// XAUTOCLAIM entries pending longer than `min_idle_ms` on any consumer of the group,
+    /// walks the pending list with `claim_cursor` across calls
+    pub fn reclaim(&mut self, conn: &mut RedisConnection) -> RedisResult<Vec<StreamEntry>> {
+        let value: Value = redis::cmd("XAUTOCLAIM").arg(&*self.stream).arg(&*self.group).arg(&*self.consumer)
+            .arg(self.min_idle_ms).arg(&*self.claim_cursor).arg("COUNT").arg(self.count).query(&mut conn.conn)?;
+        let parts = match value {
+            Value::Bulk(parts) => parts,
+            _ => return Ok(vec![]),
+        };
+        if parts.len() < 2 {
+            return Ok(vec![]);
+        }
+        self.claim_cursor = redis::from_redis_value(&parts[0])?;
+        parse_entries(&parts[1])
+    }
+
+    /// read once, reclaiming stale entries first, and hand every entry to `handler`.
+    /// entries the handler accepts are acknowledged, the others stay pending for a retry
+    pub fn poll<F>(&mut self, conn: &mut RedisConnection, mut handler: F) -> RedisResult<usize>
+        where F: FnMut(&str, &mut NetMsg) -> bool
+    {
+        let mut entries = self.reclaim(conn)?;
+        entries.extend(self.read(conn)?);
+        let mut acked = vec![];
+        for entry in &entries {
+            let mut msg = match entry.to_msg() {
+                Some(msg) => msg,
+                None => {
+                    // not produced by `stream_add`, ack so it does not come back forever
+                    acked.push(entry.id.clone());
+                    continue;
+                }
+            };
+            if handler(&entry.id, &mut msg) {
+                acked.push(entry.id.clone());
+            }
+        }
+        self.ack(conn, &acked)?;
+        Ok(entries.len())
+    }
+}
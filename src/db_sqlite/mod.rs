@@ -1,18 +1,34 @@
 use rusqlite;
 use rusqlite::{Connection, NO_PARAMS};
-use crate::db_trait::DbTrait;
+use crate::db_mysql::StmtCache;
+use crate::db_trait::{valid_name, BatchResult, BatchStat, Charset, CharacterSet, DbTrait, StmtCacheStats, StreamTimeout, Watchdog, QUERY_TIMEOUT_CODE};
 use rua_net_mgr::{NetMsg, NetResult, NetConfig};
 
 use time;
 use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
 use rusqlite::types::Value;
 
 use rua_value_list;
-use rua_value_list::{ValueType, Put, ErrorKind};
+use rua_value_list::{ValueType, Put, VarList, ErrorKind};
 
 static DB_RESULT_PROTO: &'static str = "msg_db_result";
 static LAST_INSERT_ID: &'static str = "sys_last_insert_id";
 
+/// statements rusqlite keeps per connection unless changed with `set_stmt_cache_capacity`
+static DEFAULT_STMT_CACHE_CAPACITY: usize = 64;
+
+/// count a successful `prepare_cached` of `sql` in `keys`. rusqlite keeps no counters, `keys` replays
+/// its lru with the same capacity and the same trimmed sql key, failed prepares are never cached there
+fn touch_stmt(keys: &mut StmtCache<()>, sql: &str) {
+    let sql = sql.trim();
+    if keys.get(sql).is_none() {
+        keys.insert(sql, ());
+    }
+}
+
 pub struct DbSqlite {
     pub conn: Connection,
     pub last_insert_id: u64,
@@ -20,10 +36,17 @@ pub struct DbSqlite {
     pub error: Option<rusqlite::Error>,
     pub is_connect: bool,
     pub last_use_time: f64,
+    stmt_keys: StmtCache<()>,
+    /// every statement running longer is interrupted
+    pub query_timeout: Option<Duration>,
+    /// the last statement was interrupted by its timeout
+    pub timed_out: bool,
+    watchdog: Watchdog,
 }
 
 impl DbSqlite {
     fn new(conn: Connection) -> DbSqlite {
+        conn.set_prepared_statement_cache_capacity(DEFAULT_STMT_CACHE_CAPACITY);
         DbSqlite {
             conn,
             last_insert_id: 0,
@@ -31,120 +54,163 @@ impl DbSqlite {
             error: None,
             is_connect: true,
             last_use_time: (time::OffsetDateTime::now_utc() - time::OffsetDateTime::unix_epoch()).as_seconds_f64(),
+            stmt_keys: StmtCache::new(DEFAULT_STMT_CACHE_CAPACITY),
+            query_timeout: None,
+            timed_out: false,
+            watchdog: Watchdog::new(),
         }
     }
 
+    /// open the database file at `path`, created when missing
+    pub fn open<P: AsRef<Path>>(path: P) -> rusqlite::Result<DbSqlite> {
+        Ok(DbSqlite::new(Connection::open(path)?))
+    }
+
+    /// a private database that lives as long as the connection
+    pub fn open_in_memory() -> rusqlite::Result<DbSqlite> {
+        Ok(DbSqlite::new(Connection::open_in_memory()?))
+    }
+
     pub fn check_connect(&mut self) -> NetResult<()> {
         Ok(())
     }
-}
 
-impl DbTrait for DbSqlite {
-    fn select(&mut self, sql_cmd: &str, msg: &mut NetMsg) -> NetResult<i32> {
-        self.check_connect()?;
-        let config = NetConfig::instance();
-        let mut success = 0;
-        let mut statement = match self.conn.prepare(sql_cmd) {
-            Ok(statement) => statement,
-            Err(err) => {
-                match &err {
-                    &rusqlite::Error::SqliteFailure(err, _) => success = err.extended_code,
-                    _ => (),
-                }
-                println!("err = {:?}", err);
-                self.error = Some(err);
-                return Ok(success);
+    pub fn set_query_timeout(&mut self, timeout: Option<Duration>) {
+        self.query_timeout = timeout;
+    }
+
+    /// run `func` with `timeout` in place of the connection's query timeout
+    pub fn with_timeout<R, F>(&mut self, timeout: Duration, func: F) -> R
+        where F: FnOnce(&mut DbSqlite) -> R
+    {
+        let old = ::std::mem::replace(&mut self.query_timeout, Some(timeout));
+        let result = func(self);
+        self.query_timeout = old;
+        result
+    }
+
+    /// run one statement under the query timeout, on expiry it is interrupted through
+    /// `sqlite3_interrupt` and reports `QUERY_TIMEOUT_CODE`
+    fn timed<F>(&mut self, func: F) -> NetResult<i32>
+        where F: FnOnce(&mut DbSqlite) -> NetResult<i32>
+    {
+        self.timed_out = false;
+        if let Some(timeout) = self.query_timeout {
+            let interrupt = self.interrupter();
+            self.watchdog.arm(timeout, interrupt);
+        }
+        let result = func(self);
+        let fired = self.watchdog.disarm();
+        self.timeout_result(result, fired)
+    }
+
+    fn interrupter(&self) -> impl FnOnce() + Clone + Send + 'static {
+        let handle = Arc::new(self.conn.get_interrupt_handle());
+        move || handle.interrupt()
+    }
+
+    fn timeout_result(&mut self, result: NetResult<i32>, fired: bool) -> NetResult<i32> {
+        match result {
+            Ok(success) if fired && success != 0 => {
+                self.timed_out = true;
+                Ok(QUERY_TIMEOUT_CODE)
             }
+            other => other,
+        }
+    }
+
+    /// remember `err`, returns the error code the trait methods report
+    fn set_error(&mut self, err: rusqlite::Error) -> i32 {
+        let code = match &err {
+            &rusqlite::Error::SqliteFailure(err, _) => err.extended_code,
+            _ => -1,
         };
+        self.error = Some(err);
+        code
+    }
+
+    pub fn set_stmt_cache_capacity(&mut self, capacity: usize) {
+        self.conn.set_prepared_statement_cache_capacity(capacity);
+        let _ = self.stmt_keys.resize(capacity);
+    }
 
-        let mut column_names = vec![];
-        {
-            for v in statement.column_names() {
-                column_names.push(v.to_string());
+    pub fn stmt_cache_stats(&self) -> StmtCacheStats {
+        self.stmt_keys.stats()
+    }
+
+    /// compile `sql_cmd` into the statement cache ahead of its first use. there is no handle,
+    /// statements are keyed by their sql text, pass the same text to `select_prepared` / `execute_prepared`
+    pub fn prepare(&mut self, sql_cmd: &str) -> NetResult<i32> {
+        let result = self.conn.prepare_cached(sql_cmd).map(|_| ());
+        match result {
+            Ok(()) => {
+                touch_stmt(&mut self.stmt_keys, sql_cmd);
+                Ok(0)
             }
+            Err(err) => Ok(self.set_error(err)),
         }
+    }
+
+    /// `select` through the statement cache, `params` from index 0 bind the `?` placeholders
+    pub fn select_prepared(&mut self, sql_cmd: &str, params: &VarList, msg: &mut NetMsg) -> NetResult<i32> {
+        self.timed(|db| db.run_select_prepared(sql_cmd, params, msg))
+    }
+
+    fn run_select_prepared(&mut self, sql_cmd: &str, params: &VarList, msg: &mut NetMsg) -> NetResult<i32> {
+        self.check_connect()?;
+        Ok(self.query_rows(sql_cmd, var_list_params(params, 0), msg))
+    }
+
+    /// `execute` through the statement cache
+    pub fn execute_prepared(&mut self, sql_cmd: &str, params: &VarList) -> NetResult<i32> {
+        self.timed(|db| db.run_execute_prepared(sql_cmd, params))
+    }
 
-        match statement.query(NO_PARAMS) {
-            Ok(mut rows) => {
+    fn run_execute_prepared(&mut self, sql_cmd: &str, params: &VarList) -> NetResult<i32> {
+        self.check_connect()?;
+        let result = match self.conn.prepare_cached(sql_cmd) {
+            Ok(mut statement) => {
+                touch_stmt(&mut self.stmt_keys, sql_cmd);
+                statement.execute(var_list_params(params, 0))
+            }
+            Err(err) => Err(err),
+        };
+        match result {
+            Ok(affected_rows) => {
+                self.affected_rows = affected_rows as u64;
+                self.last_insert_id = self.conn.last_insert_rowid() as u64;
                 self.error = None;
-                while let Ok(row) = rows.next() {
-                    let row = unwrap_or!(row, continue);
-                    for i in 0..row.column_count() {
-                        let column_name = &column_names[i as usize];
-                        let field = unwrap_or!(config.get_field_by_name(column_name), continue);
-                        match rua_value_list::get_type_by_name(&*field.pattern) {
-                            ValueType::ValueTypeU8 => {
-                                let value = unwrap_or!(row.get::<_, i32>(i).ok(), continue) as u8;
-                                msg.get_var_list().put(column_name.clone()).put(value);
-                            }
-                            ValueType::ValueTypeI8 => {
-                                let value = unwrap_or!(row.get::<_, i32>(i).ok(), continue) as i8;
-                                msg.get_var_list().put(column_name.clone()).put(value);
-                            }
-                            ValueType::ValueTypeU16 => {
-                                let value = unwrap_or!(row.get::<_, i32>(i).ok(), continue) as u16;
-                                msg.get_var_list().put(column_name.clone()).put(value);
-                            }
-                            ValueType::ValueTypeI16 => {
-                                let value = unwrap_or!(row.get::<_, i32>(i).ok(), continue) as i16;
-                                msg.get_var_list().put(column_name.clone()).put(value);
-                            }
-                            ValueType::ValueTypeU32 => {
-                                let value = unwrap_or!(row.get::<_, i32>(i).ok(), continue) as u32;
-                                msg.get_var_list().put(column_name.clone()).put(value);
-                            }
-                            ValueType::ValueTypeI32 => {
-                                let value = unwrap_or!(row.get::<_, i32>(i).ok(), continue) as i32;
-                                msg.get_var_list().put(column_name.clone()).put(value);
-                            }
-                            ValueType::ValueTypeU64 => {
-                                let value = unwrap_or!(row.get::<_, i32>(i).ok(), continue) as u64;
-                                msg.get_var_list().put(column_name.clone()).put(value);
-                            }
-                            ValueType::ValueTypeI64 => {
-                                let value = unwrap_or!(row.get::<_, i32>(i).ok(), continue) as i64;
-                                msg.get_var_list().put(column_name.clone()).put(value);
-                            }
-                            ValueType::ValueTypeU128 => {
-                                let value = unwrap_or!(row.get::<_, i32>(i).ok(), continue) as u128;
-                                msg.get_var_list().put(column_name.clone()).put(value);
-                            }
-                            ValueType::ValueTypeI128 => {
-                                let value = unwrap_or!(row.get::<_, i32>(i).ok(), continue) as i128;
-                                msg.get_var_list().put(column_name.clone()).put(value);
-                            }
-                            ValueType::ValueTypeF32 => {
-                                let value = unwrap_or!(row.get::<_, f64>(i).ok(), continue) as f32;
-                                msg.get_var_list().put(column_name.clone()).put(value);
-                            }
-                            ValueType::ValueTypeF64 => {
-                                let value = unwrap_or!(row.get::<_, f64>(i).ok(), continue) as f64;
-                                msg.get_var_list().put(column_name.clone()).put(value);
-                            }
-                            ValueType::ValueTypeStr => {
-                                let value: String = unwrap_or!(row.get::<_, String>(i).ok(), continue);
-                                msg.get_var_list().put(column_name.clone()).put(value);
-                            }
-                            _ => continue,
-                        }
-                    }
+                Ok(0)
+            }
+            Err(err) => Ok(self.set_error(err)),
+        }
+    }
 
-                }
+    /// run `sql_cmd` through the statement cache and write every row into `msg`. an error while
+    /// stepping, the interrupt of the timeout for one, fails the whole query
+    fn query_rows(&mut self, sql_cmd: &str, params: Vec<Value>, msg: &mut NetMsg) -> i32 {
+        let result = match self.conn.prepare_cached(sql_cmd) {
+            Ok(mut statement) => {
+                touch_stmt(&mut self.stmt_keys, sql_cmd);
+                fill_rows(&mut statement, params, msg)
             }
-            Err(err) => {
-                match &err {
-                    &rusqlite::Error::SqliteFailure(err, _) => success = err.extended_code,
-                    _ => success = -1,
-                }
-                self.error = Some(err);
-                return Ok(success);
+            Err(err) => Err(err),
+        };
+        match result {
+            Ok(()) => {
+                self.error = None;
+                0
             }
+            Err(err) => self.set_error(err),
         }
+    }
 
-        Ok(0)
+    fn run_select(&mut self, sql_cmd: &str, msg: &mut NetMsg) -> NetResult<i32> {
+        self.check_connect()?;
+        Ok(self.query_rows(sql_cmd, vec![], msg))
     }
 
-    fn execute(&mut self, sql_cmd: &str) -> NetResult<i32> {
+    fn run_execute(&mut self, sql_cmd: &str) -> NetResult<i32> {
         self.check_connect()?;
         let mut success = 0;
         match self.conn.execute(sql_cmd, NO_PARAMS) {
@@ -163,7 +229,7 @@ impl DbTrait for DbSqlite {
         Ok(success)
     }
 
-    fn insert(&mut self, sql_cmd: &str, msg: &mut NetMsg) -> NetResult<i32> {
+    fn run_insert(&mut self, sql_cmd: &str, msg: &mut NetMsg) -> NetResult<i32> {
         self.check_connect()?;
         let value = self.conn.execute(sql_cmd, NO_PARAMS);
         let mut success: i32 = 0;
@@ -183,8 +249,77 @@ impl DbTrait for DbSqlite {
         Ok(success)
     }
 
+    /// the query timeout only counts the time spent stepping, not the time the sink blocks
+    fn run_select_chunks(&mut self, sql_cmd: &str, chunk_rows: usize, sink: &mut dyn FnMut(NetMsg) -> bool) -> NetResult<i32> {
+        self.check_connect()?;
+        let chunk_rows = ::std::cmp::max(chunk_rows, 1);
+        let interrupt = self.interrupter();
+        let mut timeout = StreamTimeout::start(&mut self.watchdog, self.query_timeout, interrupt);
+        let result = match self.conn.prepare_cached(sql_cmd) {
+            Ok(mut statement) => {
+                touch_stmt(&mut self.stmt_keys, sql_cmd);
+                stream_rows(&mut statement, chunk_rows, &mut |msg| {
+                    timeout.pause();
+                    let more = sink(msg);
+                    timeout.resume();
+                    more
+                })
+            }
+            Err(err) => Err(err),
+        };
+        let fired = timeout.finish();
+        let result = match result {
+            Ok(()) => {
+                self.error = None;
+                Ok(0)
+            }
+            Err(err) => Ok(self.set_error(err)),
+        };
+        self.timeout_result(result, fired)
+    }
+
+    fn run_insert_batch(&mut self, table: &str, columns: &[&str], rows: &[VarList], result: &mut BatchResult) -> NetResult<i32> {
+        if !valid_name(table) || columns.is_empty() || columns.iter().any(|column| !valid_name(column)) {
+            fail!((rua_net_mgr::ErrorKind::IoError, "invalid batch table or columns"));
+        }
+        if rows.iter().any(|row| row.len() != columns.len()) {
+            fail!((rua_net_mgr::ErrorKind::IoError, "batch row does not match the columns"));
+        }
+        if rows.is_empty() {
+            return Ok(0);
+        }
+        self.check_connect()?;
+        let holders = vec!["?"; columns.len()];
+        let names: Vec<String> = columns.iter().map(|column| format!("\"{}\"", column)).collect();
+        let sql_cmd = format!("INSERT INTO \"{}\" ({}) VALUES ({})", table, names.join(", "), holders.join(", "));
+        match insert_rows(&mut self.conn, &mut self.stmt_keys, &sql_cmd, rows) {
+            Ok(stat) => {
+                self.affected_rows = stat.affected_rows;
+                self.last_insert_id = stat.last_id;
+                self.error = None;
+                result.batches.push(stat);
+                Ok(0)
+            }
+            Err(err) => Ok(self.set_error(err)),
+        }
+    }
+}
+
+impl DbTrait for DbSqlite {
+    fn select(&mut self, sql_cmd: &str, msg: &mut NetMsg) -> NetResult<i32> {
+        self.timed(|db| db.run_select(sql_cmd, msg))
+    }
+
+    fn execute(&mut self, sql_cmd: &str) -> NetResult<i32> {
+        self.timed(|db| db.run_execute(sql_cmd))
+    }
+
+    fn insert(&mut self, sql_cmd: &str, msg: &mut NetMsg) -> NetResult<i32> {
+        self.timed(|db| db.run_insert(sql_cmd, msg))
+    }
+
     fn begin_transaction(&mut self) -> NetResult<i32> {
-        self.execute("START TRANSACTION")
+        self.execute("BEGIN TRANSACTION")
     }
 
     fn commit_transaction(&mut self) -> NetResult<i32> {
@@ -203,8 +338,9 @@ impl DbTrait for DbSqlite {
         self.affected_rows
     }
 
-    fn get_character_set(&mut self) -> u8 {
-        0u8
+    /// sqlite text is always utf-8 here and compared byte by byte unless a column says otherwise
+    fn get_character_set(&mut self) -> CharacterSet {
+        CharacterSet::new(Charset::Utf8mb4, Some("BINARY"))
     }
 
     fn is_connected(&self) -> bool {
@@ -212,10 +348,345 @@ impl DbTrait for DbSqlite {
     }
 
     fn get_error_code(&mut self) -> i32 {
-        0
+        if self.timed_out {
+            return QUERY_TIMEOUT_CODE;
+        }
+        match &self.error {
+            &Some(rusqlite::Error::SqliteFailure(err, _)) => err.extended_code,
+            &Some(_) => -1,
+            &None => 0,
+        }
     }
 
     fn get_error_str(&mut self) -> Option<String> {
-        None
+        if self.timed_out {
+            return Some("query timed out".to_string());
+        }
+        self.error.as_ref().map(|err| format!("{}", err))
+    }
+
+    /// rows are stepped one at a time, stopping early leaves the rest unread
+    fn select_chunks(&mut self, sql_cmd: &str, chunk_rows: usize, sink: &mut dyn FnMut(NetMsg) -> bool) -> NetResult<i32> {
+        self.timed_out = false;
+        self.run_select_chunks(sql_cmd, chunk_rows, sink)
+    }
+
+    /// one prepared INSERT run for every row inside a single savepoint, so it also nests in an open transaction
+    fn insert_batch(&mut self, table: &str, columns: &[&str], rows: &[VarList], result: &mut BatchResult) -> NetResult<i32> {
+        self.timed(|db| db.run_insert_batch(table, columns, rows, result))
     }
-}
\ No newline at end of file
+}
+
+/// write the configured fields of one row into `msg` as field name and value pairs
+fn fill_row(row: &rusqlite::Row, column_names: &[String], msg: &mut NetMsg) {
+    let config = NetConfig::instance();
+    for i in 0..row.column_count() {
+        let column_name = &column_names[i as usize];
+        let field = unwrap_or!(config.get_field_by_name(column_name), continue);
+        match rua_value_list::get_type_by_name(&*field.pattern) {
+            ValueType::ValueTypeU8 => {
+                let value = unwrap_or!(row.get::<_, i32>(i).ok(), continue) as u8;
+                msg.get_var_list().put(column_name.clone()).put(value);
+            }
+            ValueType::ValueTypeI8 => {
+                let value = unwrap_or!(row.get::<_, i32>(i).ok(), continue) as i8;
+                msg.get_var_list().put(column_name.clone()).put(value);
+            }
+            ValueType::ValueTypeU16 => {
+                let value = unwrap_or!(row.get::<_, i32>(i).ok(), continue) as u16;
+                msg.get_var_list().put(column_name.clone()).put(value);
+            }
+            ValueType::ValueTypeI16 => {
+                let value = unwrap_or!(row.get::<_, i32>(i).ok(), continue) as i16;
+                msg.get_var_list().put(column_name.clone()).put(value);
+            }
+            ValueType::ValueTypeU32 => {
+                let value = unwrap_or!(row.get::<_, i32>(i).ok(), continue) as u32;
+                msg.get_var_list().put(column_name.clone()).put(value);
+            }
+            ValueType::ValueTypeI32 => {
+                let value = unwrap_or!(row.get::<_, i32>(i).ok(), continue) as i32;
+                msg.get_var_list().put(column_name.clone()).put(value);
+            }
+            ValueType::ValueTypeU64 => {
+                let value = unwrap_or!(row.get::<_, i32>(i).ok(), continue) as u64;
+                msg.get_var_list().put(column_name.clone()).put(value);
+            }
+            ValueType::ValueTypeI64 => {
+                let value = unwrap_or!(row.get::<_, i32>(i).ok(), continue) as i64;
+                msg.get_var_list().put(column_name.clone()).put(value);
+            }
+            ValueType::ValueTypeU128 => {
+                let value = unwrap_or!(row.get::<_, i32>(i).ok(), continue) as u128;
+                msg.get_var_list().put(column_name.clone()).put(value);
+            }
+            ValueType::ValueTypeI128 => {
+                let value = unwrap_or!(row.get::<_, i32>(i).ok(), continue) as i128;
+                msg.get_var_list().put(column_name.clone()).put(value);
+            }
+            ValueType::ValueTypeF32 => {
+                let value = unwrap_or!(row.get::<_, f64>(i).ok(), continue) as f32;
+                msg.get_var_list().put(column_name.clone()).put(value);
+            }
+            ValueType::ValueTypeF64 => {
+                let value = unwrap_or!(row.get::<_, f64>(i).ok(), continue) as f64;
+                msg.get_var_list().put(column_name.clone()).put(value);
+            }
+            ValueType::ValueTypeStr => {
+                let value: String = unwrap_or!(row.get::<_, String>(i).ok(), continue);
+                msg.get_var_list().put(column_name.clone()).put(value);
+            }
+            _ => continue,
+        }
+    }
+}
+
+fn fill_rows(statement: &mut rusqlite::Statement, params: Vec<Value>, msg: &mut NetMsg) -> rusqlite::Result<()> {
+    let column_names: Vec<String> = statement.column_names().iter().map(|v| v.to_string()).collect();
+    let mut rows = statement.query(params)?;
+    while let Some(row) = rows.next()? {
+        fill_row(&row, &column_names, msg);
+    }
+    Ok(())
+}
+
+fn stream_rows(statement: &mut rusqlite::Statement, chunk_rows: usize, sink: &mut dyn FnMut(NetMsg) -> bool) -> rusqlite::Result<()> {
+    let column_names: Vec<String> = statement.column_names().iter().map(|v| v.to_string()).collect();
+    let mut rows = statement.query(NO_PARAMS)?;
+    let mut msg = NetMsg::new();
+    let mut count = 0;
+    while let Some(row) = rows.next()? {
+        fill_row(&row, &column_names, &mut msg);
+        count += 1;
+        if count == chunk_rows {
+            count = 0;
+            if !sink(::std::mem::replace(&mut msg, NetMsg::new())) {
+                return Ok(());
+            }
+        }
+    }
+    if count > 0 {
+        sink(msg);
+    }
+    Ok(())
+}
+
+fn insert_rows(conn: &mut Connection, stmt_keys: &mut StmtCache<()>, sql_cmd: &str, rows: &[VarList]) -> rusqlite::Result<BatchStat> {
+    let savepoint = conn.savepoint()?;
+    let mut stat = BatchStat { rows: rows.len(), ..BatchStat::default() };
+    {
+        let mut statement = savepoint.prepare_cached(sql_cmd)?;
+        touch_stmt(stmt_keys, sql_cmd);
+        for row in rows {
+            stat.affected_rows += statement.execute(var_list_params(row, 0))? as u64;
+            let id = savepoint.last_insert_rowid() as u64;
+            if stat.first_id == 0 {
+                stat.first_id = id;
+            }
+            stat.last_id = id;
+        }
+    }
+    savepoint.commit()?;
+    Ok(stat)
+}
+
+/// the values of `var_list` from `start` as positional statement params
+fn var_list_params(var_list: &VarList, start: usize) -> Vec<Value> {
+    let mut params = vec![];
+    for i in start..var_list.len() {
+        let value = match var_list.get_type(i) {
+            ValueType::ValueTypeU8 => var_list.get_u8(i).map(|v| Value::Integer(v as i64)),
+            ValueType::ValueTypeI8 => var_list.get_i8(i).map(|v| Value::Integer(v as i64)),
+            ValueType::ValueTypeU16 => var_list.get_u16(i).map(|v| Value::Integer(v as i64)),
+            ValueType::ValueTypeI16 => var_list.get_i16(i).map(|v| Value::Integer(v as i64)),
+            ValueType::ValueTypeU32 => var_list.get_u32(i).map(|v| Value::Integer(v as i64)),
+            ValueType::ValueTypeI32 => var_list.get_i32(i).map(|v| Value::Integer(v as i64)),
+            ValueType::ValueTypeU64 => var_list.get_u64(i).map(|v| Value::Integer(v as i64)),
+            ValueType::ValueTypeI64 => var_list.get_i64(i).map(Value::Integer),
+            ValueType::ValueTypeU128 => var_list.get_u128(i).map(|v| Value::Text(v.to_string())),
+            ValueType::ValueTypeI128 => var_list.get_i128(i).map(|v| Value::Text(v.to_string())),
+            ValueType::ValueTypeF32 => var_list.get_f32(i).map(|v| Value::Real(v as f64)),
+            ValueType::ValueTypeF64 => var_list.get_f64(i).map(Value::Real),
+            ValueType::ValueTypeStr => var_list.get_str(i).map(|v| Value::Text(v.to_string())),
+            ValueType::ValueTypeObj => var_list.get_obj(i).map(|v| Value::Text(v.to_string())),
+            _ => None,
+        };
+        params.push(value.unwrap_or(Value::Null));
+    }
+    params
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user_db() -> DbSqlite {
+        let mut db = DbSqlite::open_in_memory().unwrap();
+        assert_eq!(db.execute("CREATE TABLE user (id INTEGER PRIMARY KEY, name TEXT UNIQUE, level INTEGER)").unwrap(), 0);
+        db
+    }
+
+    fn user_row(name: &str, level: u32) -> VarList {
+        let mut row = VarList::new();
+        row.put(name.to_string()).put(level);
+        row
+    }
+
+    fn count_rows(db: &DbSqlite) -> i64 {
+        db.conn.query_row("SELECT COUNT(*) FROM user", NO_PARAMS, |row| row.get(0)).unwrap()
+    }
+
+    #[test]
+    fn prepared_statements_hit_the_cache() {
+        let mut db = user_db();
+        let insert = "INSERT INTO user (name, level) VALUES (?, ?)";
+        let mut params = VarList::new();
+        params.put("ann".to_string()).put(3u32);
+        assert_eq!(db.execute_prepared(insert, &params).unwrap(), 0);
+        assert_eq!((db.affected_rows, db.last_insert_id), (1, 1));
+
+        let select = "SELECT id, name FROM user WHERE level = ?";
+        assert_eq!(db.prepare(select).unwrap(), 0);
+        let mut params = VarList::new();
+        params.put(3u32);
+        let mut msg = NetMsg::new();
+        assert_eq!(db.select_prepared(select, &params, &mut msg).unwrap(), 0);
+        // statements are keyed by the trimmed sql
+        assert_eq!(db.select_prepared(&format!("  {}\n", select), &params, &mut msg).unwrap(), 0);
+
+        let stats = db.stmt_cache_stats();
+        assert_eq!((stats.hits, stats.misses, stats.evictions, stats.size), (2, 2, 0, 2));
+        assert_eq!(stats.capacity, DEFAULT_STMT_CACHE_CAPACITY);
+    }
+
+    #[test]
+    fn failed_prepare_is_not_counted() {
+        let mut db = user_db();
+        assert_ne!(db.prepare("SELECT * FROM missing").unwrap(), 0);
+        assert!(db.get_error_str().is_some());
+        let stats = db.stmt_cache_stats();
+        assert_eq!((stats.hits, stats.misses, stats.size), (0, 0, 0));
+    }
+
+    #[test]
+    fn stmt_cache_evicts_least_recently_used() {
+        let mut db = user_db();
+        db.set_stmt_cache_capacity(2);
+        assert_eq!(db.prepare("SELECT 1").unwrap(), 0);
+        assert_eq!(db.prepare("SELECT 2").unwrap(), 0);
+        assert_eq!(db.prepare("SELECT 1").unwrap(), 0);
+        assert_eq!(db.prepare("SELECT 3").unwrap(), 0);
+        assert_eq!(db.prepare("SELECT 2").unwrap(), 0);
+
+        let stats = db.stmt_cache_stats();
+        assert_eq!((stats.hits, stats.misses, stats.evictions, stats.size, stats.capacity), (1, 4, 2, 2, 2));
+    }
+
+    #[test]
+    fn insert_batch_reports_every_call() {
+        let mut db = user_db();
+        let mut result = BatchResult::default();
+        let rows = vec![user_row("ann", 1), user_row("bob", 2), user_row("cid", 3)];
+        assert_eq!(db.insert_batch("user", &["name", "level"], &rows, &mut result).unwrap(), 0);
+        let rows = vec![user_row("dan", 4), user_row("eve", 5)];
+        assert_eq!(db.insert_batch("user", &["name", "level"], &rows, &mut result).unwrap(), 0);
+
+        let stats: Vec<(usize, u64, u64, u64)> = result.batches.iter()
+            .map(|stat| (stat.rows, stat.affected_rows, stat.first_id, stat.last_id))
+            .collect();
+        assert_eq!(stats, vec![(3, 3, 1, 3), (2, 2, 4, 5)]);
+        assert_eq!((db.affected_rows, db.last_insert_id), (2, 5));
+        assert_eq!(count_rows(&db), 5);
+        assert_eq!(db.insert_batch("user", &["name", "level"], &[], &mut result).unwrap(), 0);
+        assert_eq!(result.batches.len(), 2);
+    }
+
+    #[test]
+    fn failed_row_rolls_back_the_batch() {
+        let mut db = user_db();
+        let mut result = BatchResult::default();
+        assert_eq!(db.insert_batch("user", &["name", "level"], &[user_row("ann", 1)], &mut result).unwrap(), 0);
+        let rows = vec![user_row("bob", 2), user_row("cid", 3), user_row("ann", 4)];
+        assert_ne!(db.insert_batch("user", &["name", "level"], &rows, &mut result).unwrap(), 0);
+        assert_ne!(db.get_error_code(), 0);
+        assert_eq!(result.batches.len(), 1);
+        assert_eq!(count_rows(&db), 1);
+        assert!(db.insert_batch("user", &["name", "bogus name"], &rows, &mut result).is_err());
+        assert!(db.insert_batch("user", &["name"], &rows, &mut result).is_err());
+    }
+
+    #[test]
+    fn insert_batch_nests_in_a_transaction() {
+        let mut db = user_db();
+        let mut result = BatchResult::default();
+        assert_eq!(db.begin_transaction().unwrap(), 0);
+        let rows = vec![user_row("ann", 1), user_row("bob", 2)];
+        assert_eq!(db.insert_batch("user", &["name", "level"], &rows, &mut result).unwrap(), 0);
+        assert_eq!(count_rows(&db), 2);
+        assert_eq!(db.rollback_transaction().unwrap(), 0);
+        assert_eq!(count_rows(&db), 0);
+    }
+
+    #[test]
+    fn query_timeout_interrupts_and_recovers() {
+        let mut db = user_db();
+        db.set_query_timeout(Some(Duration::from_millis(50)));
+        let endless = "WITH RECURSIVE c(x) AS (SELECT 1 UNION ALL SELECT x + 1 FROM c) SELECT count(*) FROM c";
+        let mut msg = NetMsg::new();
+        assert_eq!(db.select(endless, &mut msg).unwrap(), QUERY_TIMEOUT_CODE);
+        assert!(db.timed_out);
+        assert_eq!(db.get_error_code(), QUERY_TIMEOUT_CODE);
+
+        let mut msg = NetMsg::new();
+        assert_eq!(db.select("SELECT 1", &mut msg).unwrap(), 0);
+        assert!(!db.timed_out);
+        assert_eq!(db.with_timeout(Duration::from_millis(20), |db| db.select(endless, &mut msg)).unwrap(), QUERY_TIMEOUT_CODE);
+        assert_eq!(db.query_timeout, Some(Duration::from_millis(50)));
+    }
+
+    fn number_db(values: &[i64]) -> DbSqlite {
+        let mut db = DbSqlite::open_in_memory().unwrap();
+        assert_eq!(db.execute("CREATE TABLE t (id INTEGER PRIMARY KEY, v INTEGER)").unwrap(), 0);
+        for v in values {
+            assert_eq!(db.execute(&format!("INSERT INTO t (v) VALUES ({})", v)).unwrap(), 0);
+        }
+        db
+    }
+
+    fn chunk_calls(db: &mut DbSqlite, sql_cmd: &str, chunk_rows: usize, stop_after: usize) -> (i32, usize) {
+        let mut calls = 0;
+        let success = db.select_chunks(sql_cmd, chunk_rows, &mut |_msg| {
+            calls += 1;
+            calls < stop_after
+        }).unwrap();
+        (success, calls)
+    }
+
+    #[test]
+    fn select_chunks_splits_rows() {
+        let mut db = number_db(&[1, 2, 3, 4, 5]);
+        let sql_cmd = "SELECT v FROM t ORDER BY id";
+        assert_eq!(chunk_calls(&mut db, sql_cmd, 2, usize::max_value()), (0, 3));
+        assert_eq!(chunk_calls(&mut db, sql_cmd, 5, usize::max_value()), (0, 1));
+        assert_eq!(chunk_calls(&mut db, sql_cmd, 0, usize::max_value()), (0, 5));
+        assert_eq!(chunk_calls(&mut db, "SELECT v FROM t WHERE v > 9", 2, usize::max_value()), (0, 0));
+    }
+
+    #[test]
+    fn select_chunks_stops_when_the_sink_cancels() {
+        let mut db = number_db(&[1, 2, 3, 4, 5]);
+        assert_eq!(chunk_calls(&mut db, "SELECT v FROM t ORDER BY id", 2, 1), (0, 1));
+        // the cached statement was reset and runs again from the start
+        assert_eq!(chunk_calls(&mut db, "SELECT v FROM t ORDER BY id", 2, usize::max_value()), (0, 3));
+    }
+
+    #[test]
+    fn select_chunks_reports_a_row_error() {
+        let mut db = number_db(&[1, -2, ::std::i64::MIN, 4]);
+        let (success, calls) = chunk_calls(&mut db, "SELECT abs(v) FROM t ORDER BY id", 1, usize::max_value());
+        assert_ne!(success, 0);
+        assert_eq!(calls, 2);
+        assert_eq!(db.get_error_code(), success);
+        assert!(!db.timed_out);
+    }
+}
@@ -1,4 +1,11 @@
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
 use rua_net_mgr::{NetMsg, NetResult};
+use rua_value_list::VarList;
+
+/// error code of a query cancelled by its timeout, apart from the backend codes and the generic -1
+pub static QUERY_TIMEOUT_CODE: i32 = -2;
 
 pub trait DbTrait {
     fn select(&mut self, sql_cmd: &str, msg: &mut NetMsg) -> NetResult<i32>;
@@ -9,8 +16,385 @@ pub trait DbTrait {
     fn rollback_transaction(&mut self) -> NetResult<i32>;
     fn get_last_insert_id(&mut self) -> u64;
     fn get_affected_rows(&mut self) -> u64;
-    fn get_character_set(&mut self) -> u8;
+    fn get_character_set(&mut self) -> CharacterSet;
     fn is_connected(&self) -> bool;
     fn get_error_code(&mut self) -> i32;
     fn get_error_str(&mut self) -> Option<String>;
-}
\ No newline at end of file
+    /// run `sql_cmd` and hand its rows to `sink` in messages of at most `chunk_rows` rows instead of
+    /// one message for the whole result. `sink` may block to hold the query back (a `SyncSender::send`
+    /// for instance) and returns false to stop early
+    fn select_chunks(&mut self, sql_cmd: &str, chunk_rows: usize, sink: &mut dyn FnMut(NetMsg) -> bool) -> NetResult<i32>;
+    /// insert `rows`, each holding one value per entry of `columns`, in as few round trips as the
+    /// backend allows. returns the error code of the first failed batch, `result` keeps the ones before it
+    fn insert_batch(&mut self, table: &str, columns: &[&str], rows: &[VarList], result: &mut BatchResult) -> NetResult<i32>;
+
+    /// `value` as a string literal of this backend's sql dialect
+    fn quote_value(&self, value: &str) -> String {
+        quote_value(value)
+    }
+}
+
+/// table and column names we splice into generated sql
+pub fn valid_name(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// `value` as a standard sql string literal, quotes doubled
+pub fn quote_value(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "''"))
+}
+
+/// prepared statement cache counters of one connection
+#[derive(Debug, Clone, Copy, Default)]
+pub struct StmtCacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    pub size: usize,
+    pub capacity: usize,
+}
+
+impl StmtCacheStats {
+    pub fn hit_rate(&self) -> f64 {
+        let total = self.hits + self.misses;
+        if total == 0 {
+            0.0
+        } else {
+            self.hits as f64 / total as f64
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Charset {
+    Utf8mb4,
+    /// mysql utf8mb3, no 4 byte characters
+    Utf8,
+    /// mysql latin1 is cp1252
+    Latin1,
+    Ascii,
+    Binary,
+    Other(String),
+}
+
+impl Charset {
+    pub fn from_name(name: &str) -> Charset {
+        match &*name.to_ascii_lowercase() {
+            "utf8mb4" => Charset::Utf8mb4,
+            "utf8" | "utf8mb3" => Charset::Utf8,
+            "latin1" => Charset::Latin1,
+            "ascii" => Charset::Ascii,
+            "binary" => Charset::Binary,
+            other => Charset::Other(other.to_string()),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        match *self {
+            Charset::Utf8mb4 => "utf8mb4",
+            Charset::Utf8 => "utf8",
+            Charset::Latin1 => "latin1",
+            Charset::Ascii => "ascii",
+            Charset::Binary => "binary",
+            Charset::Other(ref name) => name,
+        }
+    }
+
+    pub fn default_collation(&self) -> String {
+        match *self {
+            Charset::Utf8mb4 => "utf8mb4_general_ci".to_string(),
+            Charset::Utf8 => "utf8_general_ci".to_string(),
+            Charset::Latin1 => "latin1_swedish_ci".to_string(),
+            Charset::Ascii => "ascii_general_ci".to_string(),
+            Charset::Binary => "binary".to_string(),
+            Charset::Other(ref name) => format!("{}_general_ci", name),
+        }
+    }
+
+    /// `decode` knows this charset, `Other` would be read as utf-8 and lose its non ascii text
+    pub fn is_supported(&self) -> bool {
+        match *self {
+            Charset::Other(_) => false,
+            _ => true,
+        }
+    }
+
+    /// text of a string column sent in this charset
+    pub fn decode(&self, data: Vec<u8>) -> Option<String> {
+        match *self {
+            Charset::Latin1 => Some(data.into_iter().map(cp1252_char).collect()),
+            Charset::Ascii => {
+                if data.is_ascii() { String::from_utf8(data).ok() } else { None }
+            }
+            _ => String::from_utf8(data).ok(),
+        }
+    }
+}
+
+/// cp1252 differs from iso-8859-1 only in 0x80-0x9f
+fn cp1252_char(byte: u8) -> char {
+    static HIGH: [u16; 32] = [
+        0x20AC, 0x0081, 0x201A, 0x0192, 0x201E, 0x2026, 0x2020, 0x2021,
+        0x02C6, 0x2030, 0x0160, 0x2039, 0x0152, 0x008D, 0x017D, 0x008F,
+        0x0090, 0x2018, 0x2019, 0x201C, 0x201D, 0x2022, 0x2013, 0x2014,
+        0x02DC, 0x2122, 0x0161, 0x203A, 0x0153, 0x009D, 0x017E, 0x0178,
+    ];
+    match byte {
+        0x80..=0x9f => ::std::char::from_u32(HIGH[(byte - 0x80) as usize] as u32).unwrap_or('\u{fffd}'),
+        _ => byte as char,
+    }
+}
+
+/// character set and collation of a connection
+#[derive(Debug, Clone, PartialEq)]
+pub struct CharacterSet {
+    pub charset: Charset,
+    pub collation: String,
+}
+
+impl CharacterSet {
+    pub fn new(charset: Charset, collation: Option<&str>) -> CharacterSet {
+        let collation = match collation {
+            Some(collation) => collation.to_string(),
+            None => charset.default_collation(),
+        };
+        CharacterSet { charset, collation }
+    }
+}
+
+impl Default for CharacterSet {
+    fn default() -> CharacterSet {
+        CharacterSet::new(Charset::Utf8mb4, None)
+    }
+}
+
+/// one statement sent by `insert_batch`
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BatchStat {
+    pub rows: usize,
+    pub affected_rows: u64,
+    /// ids generated for the batch, 0 when the table has no auto increment key
+    pub first_id: u64,
+    /// on mysql `first_id` plus `auto_increment_increment` for every further row,
+    /// InnoDB hands a multi row VALUES insert its ids as one run
+    pub last_id: u64,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct BatchResult {
+    pub batches: Vec<BatchStat>,
+}
+
+impl BatchResult {
+    pub fn affected_rows(&self) -> u64 {
+        self.batches.iter().map(|batch| batch.affected_rows).sum()
+    }
+}
+
+enum Timer {
+    Arm(Duration, Box<dyn FnOnce() + Send>),
+    Disarm,
+}
+
+/// runs a cancel callback on its timer thread unless `disarm` is called within the timeout.
+/// the thread is started on the first `arm` and reused for every query of the connection
+#[derive(Default)]
+pub struct Watchdog {
+    timer: Option<(mpsc::Sender<Timer>, mpsc::Receiver<bool>)>,
+    armed: bool,
+}
+
+impl Watchdog {
+    pub fn new() -> Watchdog {
+        Watchdog::default()
+    }
+
+    pub fn arm<F>(&mut self, timeout: Duration, cancel: F)
+        where F: FnOnce() + Send + 'static
+    {
+        self.disarm();
+        let (timer, _) = self.timer.get_or_insert_with(|| {
+            let (timer, commands) = mpsc::channel();
+            let (report, fired) = mpsc::channel();
+            thread::spawn(move || watch(commands, report));
+            (timer, fired)
+        });
+        self.armed = timer.send(Timer::Arm(timeout, Box::new(cancel))).is_ok();
+    }
+
+    /// stop waiting, true when the timeout already fired. waits for a running cancel so it can
+    /// never hit the next query
+    pub fn disarm(&mut self) -> bool {
+        if !self.armed {
+            return false;
+        }
+        self.armed = false;
+        let fired = match self.timer {
+            Some((ref timer, ref fired)) if timer.send(Timer::Disarm).is_ok() => fired.recv().ok(),
+            _ => None,
+        };
+        // a cancel that panicked took the thread with it, the next `arm` starts a new one
+        if fired.is_none() {
+            self.timer = None;
+        }
+        fired.unwrap_or(false)
+    }
+}
+
+/// the timer thread, answers every `Disarm` of an armed timer with whether it fired
+fn watch(commands: mpsc::Receiver<Timer>, report: mpsc::Sender<bool>) {
+    while let Ok(command) = commands.recv() {
+        let (timeout, cancel) = match command {
+            Timer::Arm(timeout, cancel) => (timeout, cancel),
+            Timer::Disarm => continue,
+        };
+        let fired = match commands.recv_timeout(timeout) {
+            Ok(_) => false,
+            Err(RecvTimeoutError::Timeout) => {
+                cancel();
+                if commands.recv().is_err() {
+                    return;
+                }
+                true
+            }
+            Err(RecvTimeoutError::Disconnected) => return,
+        };
+        if report.send(fired).is_err() {
+            return;
+        }
+    }
+}
+
+/// a query timeout for streams: only the time between `resume` and `pause` counts, so a
+/// consumer blocking between chunks does not use it up
+pub struct StreamTimeout<'a, F> {
+    watchdog: &'a mut Watchdog,
+    remaining: Option<Duration>,
+    started: Instant,
+    running: bool,
+    fired: bool,
+    cancel: F,
+}
+
+impl<'a, F> StreamTimeout<'a, F> where F: FnOnce() + Clone + Send + 'static {
+    /// running right away, `None` never times out
+    pub fn start(watchdog: &'a mut Watchdog, timeout: Option<Duration>, cancel: F) -> StreamTimeout<'a, F> {
+        let mut stream_timeout = StreamTimeout {
+            watchdog,
+            remaining: timeout,
+            started: Instant::now(),
+            running: false,
+            fired: false,
+            cancel,
+        };
+        stream_timeout.resume();
+        stream_timeout
+    }
+
+    pub fn pause(&mut self) {
+        if self.running {
+            self.running = false;
+            self.fired |= self.watchdog.disarm();
+            let elapsed = self.started.elapsed();
+            self.remaining = self.remaining.map(|left| left.checked_sub(elapsed).unwrap_or_default());
+        }
+    }
+
+    pub fn resume(&mut self) {
+        if let (false, Some(left)) = (self.running, self.remaining) {
+            self.running = true;
+            self.started = Instant::now();
+            self.watchdog.arm(left, self.cancel.clone());
+        }
+    }
+
+    /// stop for good, true when the timeout fired at any point
+    pub fn finish(mut self) -> bool {
+        self.pause();
+        self.fired
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn watchdog_reuse() {
+        let cancels = Arc::new(AtomicUsize::new(0));
+        let mut watchdog = Watchdog::new();
+        assert!(!watchdog.disarm());
+
+        let count = cancels.clone();
+        watchdog.arm(Duration::from_secs(10), move || { count.fetch_add(1, Ordering::SeqCst); });
+        assert!(!watchdog.disarm());
+        assert_eq!(cancels.load(Ordering::SeqCst), 0);
+
+        for _ in 0..2 {
+            let count = cancels.clone();
+            watchdog.arm(Duration::from_millis(10), move || { count.fetch_add(1, Ordering::SeqCst); });
+            thread::sleep(Duration::from_millis(200));
+            assert!(watchdog.disarm());
+        }
+        assert_eq!(cancels.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn stream_timeout_pauses() {
+        let cancels = Arc::new(AtomicUsize::new(0));
+        let mut watchdog = Watchdog::new();
+        let count = cancels.clone();
+        let mut timeout = StreamTimeout::start(&mut watchdog, Some(Duration::from_millis(200)),
+                                               move || { count.fetch_add(1, Ordering::SeqCst); });
+        timeout.pause();
+        thread::sleep(Duration::from_millis(300));
+        timeout.resume();
+        assert!(!timeout.finish());
+        assert_eq!(cancels.load(Ordering::SeqCst), 0);
+
+        let count = cancels.clone();
+        let timeout = StreamTimeout::start(&mut watchdog, Some(Duration::from_millis(10)),
+                                           move || { count.fetch_add(1, Ordering::SeqCst); });
+        thread::sleep(Duration::from_millis(200));
+        assert!(timeout.finish());
+        assert_eq!(cancels.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn charset_names() {
+        assert_eq!(Charset::from_name("UTF8MB4"), Charset::Utf8mb4);
+        assert_eq!(Charset::from_name("utf8mb3"), Charset::Utf8);
+        assert_eq!(Charset::from_name("gbk"), Charset::Other("gbk".to_string()));
+        assert_eq!(Charset::Latin1.name(), "latin1");
+        assert!(!Charset::from_name("gbk").is_supported());
+        assert!(Charset::Binary.is_supported());
+        assert_eq!(CharacterSet::default().collation, "utf8mb4_general_ci");
+        assert_eq!(CharacterSet::new(Charset::Latin1, Some("latin1_bin")).collation, "latin1_bin");
+    }
+
+    #[test]
+    fn decode_utf8() {
+        assert_eq!(Charset::Utf8mb4.decode("héllo 😀".as_bytes().to_vec()), Some("héllo 😀".to_string()));
+        assert_eq!(Charset::Utf8.decode(vec![0xff, 0x41]), None);
+    }
+
+    #[test]
+    fn decode_latin1_as_cp1252() {
+        assert_eq!(Charset::Latin1.decode(vec![b'c', 0xe9, 0x80, 0x99, 0x81]), Some("c\u{e9}\u{20ac}\u{2122}\u{81}".to_string()));
+    }
+
+    #[test]
+    fn decode_ascii() {
+        assert_eq!(Charset::Ascii.decode(b"plain".to_vec()), Some("plain".to_string()));
+        assert_eq!(Charset::Ascii.decode(vec![b'a', 0xe9]), None);
+    }
+
+    #[test]
+    fn quoting() {
+        assert_eq!(quote_value("it's"), "'it''s'");
+        assert!(valid_name("user_1"));
+        assert!(!valid_name("user 1") && !valid_name("") && !valid_name("a;b"));
+    }
+}
@@ -0,0 +1,281 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use redis::RedisResult;
+use crate::db_mysql::DbMysql;
+use crate::db_trait::{DbTrait, valid_name};
+use crate::db_redis::{RedisConnection, RedisLock, RedisPool, ScriptRegistry};
+use crate::db_redis::cluster;
+
+static WRITE_SCRIPT_NAME: &'static str = "rua_write_behind_write";
+static COMMIT_SCRIPT_NAME: &'static str = "rua_write_behind_commit";
+
+/// KEYS: row hash, dirty zset, sequence. ARGV: dirty member, then field / value pairs
+static WRITE_SCRIPT: &'static str = r"
+redis.call('HSET', KEYS[1], unpack(ARGV, 2))
+local seq = redis.call('INCR', KEYS[3])
+redis.call('ZADD', KEYS[2], seq, ARGV[1])
+return seq";
+
+/// KEYS: dirty zset. ARGV: member / score pairs read before the flush.
+/// a member written again during the flush has a newer score and stays dirty
+static COMMIT_SCRIPT: &'static str = r"
+local n = 0
+for i = 1, #ARGV, 2 do
+    if redis.call('ZSCORE', KEYS[1], ARGV[i]) == ARGV[i + 1] then
+        redis.call('ZREM', KEYS[1], ARGV[i])
+        n = n + 1
+    end
+end
+return n";
+
+#[derive(Debug, Clone)]
+pub struct WriteBehindConfig {
+    pub prefix: String,
+    /// dirty sets are split in shards, each flushed by one worker at a time
+    pub shards: u16,
+    /// max dirty rows flushed by one transaction
+    pub batch_size: usize,
+    pub flush_interval: Duration,
+    pub max_retries: u32,
+    /// first retry delay, doubled on every further attempt
+    pub retry_delay: Duration,
+    pub lock_ttl: Duration,
+}
+
+impl Default for WriteBehindConfig {
+    fn default() -> WriteBehindConfig {
+        WriteBehindConfig {
+            prefix: "rua_wb".to_string(),
+            shards: 8,
+            batch_size: 200,
+            flush_interval: Duration::from_millis(500),
+            max_retries: 3,
+            retry_delay: Duration::from_millis(100),
+            lock_ttl: Duration::from_secs(30),
+        }
+    }
+}
+
+/// write-behind persistence: rows land in redis hashes right away and are marked dirty,
+/// worker threads drain the dirty sets into batched `INSERT ... ON DUPLICATE KEY UPDATE`
+/// on mysql. a row only leaves its dirty set after the mysql commit, so a crash at any
+/// point leaves it dirty and the next worker flushes it again
+pub struct WriteBehind {
+    pub config: WriteBehindConfig,
+    /// table name to primary key column
+    pub tables: HashMap<String, String>,
+    pub stop: Arc<AtomicBool>,
+}
+
+impl WriteBehind {
+    pub fn new(config: WriteBehindConfig) -> WriteBehind {
+        let registry = ScriptRegistry::instance();
+        registry.register(WRITE_SCRIPT_NAME, WRITE_SCRIPT);
+        registry.register(COMMIT_SCRIPT_NAME, COMMIT_SCRIPT);
+        WriteBehind {
+            config,
+            tables: HashMap::new(),
+            stop: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    pub fn register_table(&mut self, table: &str, key_column: &str) -> bool {
+        if !valid_name(table) || !valid_name(key_column) {
+            return false;
+        }
+        self.tables.insert(table.to_string(), key_column.to_string());
+        true
+    }
+
+    fn shard_of(&self, member: &str) -> u16 {
+        cluster::key_slot(member) % ::std::cmp::max(self.config.shards, 1)
+    }
+
+    /// every key of a shard shares the `{prefix:shard}` hash tag so the scripts stay on one slot
+    fn row_key(&self, shard: u16, table: &str, id: &str) -> String {
+        format!("{{{}:{}}}:row:{}:{}", self.config.prefix, shard, table, id)
+    }
+
+    fn dirty_key(&self, shard: u16) -> String {
+        format!("{{{}:{}}}:dirty", self.config.prefix, shard)
+    }
+
+    fn seq_key(&self, shard: u16) -> String {
+        format!("{{{}:{}}}:seq", self.config.prefix, shard)
+    }
+
+    fn lock_key(&self, shard: u16) -> String {
+        format!("{{{}:{}}}:lock", self.config.prefix, shard)
+    }
+
+    /// store `fields` of row `id` in redis and mark it dirty
+    pub fn write(&self, conn: &mut RedisConnection, table: &str, id: &str, fields: &[(&str, String)]) -> RedisResult<()> {
+        if !self.tables.contains_key(table) {
+            fail!((redis::ErrorKind::ClientError, "table not registered"));
+        }
+        if fields.is_empty() || fields.iter().any(|(name, _)| !valid_name(name)) {
+            fail!((redis::ErrorKind::ClientError, "invalid column name"));
+        }
+        let member = format!("{}:{}", table, id);
+        let shard = self.shard_of(&member);
+        let mut invocation = ScriptRegistry::instance().invocation(WRITE_SCRIPT_NAME);
+        invocation.key(self.row_key(shard, table, id)).key(self.dirty_key(shard))
+            .key(self.seq_key(shard)).arg(&*member);
+        for (name, value) in fields {
+            invocation.arg(*name).arg(&**value);
+        }
+        invocation.invoke::<i64>(conn)?;
+        Ok(())
+    }
+
+    /// current row state, redis is ahead of mysql while the row is dirty
+    pub fn read(&self, conn: &mut RedisConnection, table: &str, id: &str) -> RedisResult<HashMap<String, String>> {
+        let shard = self.shard_of(&format!("{}:{}", table, id));
+        redis::cmd("HGETALL").arg(self.row_key(shard, table, id)).query(&mut conn.conn)
+    }
+
+    /// flush one batch of `shard`, returns the rows committed
+    pub fn flush_shard(&self, conn: &mut RedisConnection, db: &mut DbMysql, shard: u16) -> RedisResult<usize> {
+        let lock = unwrap_or!(RedisLock::try_lock(conn, &self.lock_key(shard), self.config.lock_ttl)?, return Ok(0));
+        let result = self.flush_locked(conn, db, shard);
+        let _ = lock.release(conn);
+        result
+    }
+
+    fn flush_locked(&self, conn: &mut RedisConnection, db: &mut DbMysql, shard: u16) -> RedisResult<usize> {
+        let dirty_key = self.dirty_key(shard);
+        let dirty: Vec<(String, String)> = redis::cmd("ZRANGE").arg(&*dirty_key).arg(0)
+            .arg(self.config.batch_size as isize - 1).arg("WITHSCORES").query(&mut conn.conn)?;
+        if dirty.is_empty() {
+            return Ok(0);
+        }
+
+        // coalesce rows with the same column set into one multi row upsert. members of tables
+        // this worker has not registered (yet) stay dirty for a worker that knows them
+        let mut groups: HashMap<(String, Vec<String>), Vec<(String, HashMap<String, String>)>> = HashMap::new();
+        let mut flushed = vec![];
+        for (member, score) in &dirty {
+            let mut parts = member.splitn(2, ':');
+            let table = unwrap_or!(parts.next(), continue);
+            let id = unwrap_or!(parts.next(), continue);
+            if !self.tables.contains_key(table) {
+                continue;
+            }
+            let row: HashMap<String, String> = redis::cmd("HGETALL")
+                .arg(self.row_key(shard, table, id)).query(&mut conn.conn)?;
+            let mut columns: Vec<String> = row.keys().filter(|c| valid_name(c)).cloned().collect();
+            columns.sort();
+            groups.entry((table.to_string(), columns)).or_insert_with(Vec::new).push((id.to_string(), row));
+            flushed.push((member, score));
+        }
+        if flushed.is_empty() {
+            return Ok(0);
+        }
+
+        let mut statements = vec![];
+        for ((table, columns), rows) in &groups {
+            let key_column = &self.tables[table];
+            let columns: Vec<&String> = columns.iter().filter(|c| *c != key_column).collect();
+            let mut sql = format!("INSERT INTO `{}` (`{}`", table, key_column);
+            for column in &columns {
+                sql.push_str(&format!(", `{}`", column));
+            }
+            sql.push_str(") VALUES ");
+            for (i, (id, row)) in rows.iter().enumerate() {
+                if i > 0 {
+                    sql.push_str(", ");
+                }
+                sql.push('(');
+                sql.push_str(&db.quote_value(id));
+                for column in &columns {
+                    sql.push_str(", ");
+                    sql.push_str(&db.quote_value(&row[*column]));
+                }
+                sql.push(')');
+            }
+            if columns.is_empty() {
+                sql.push_str(&format!(" ON DUPLICATE KEY UPDATE `{}` = `{}`", key_column, key_column));
+            } else {
+                sql.push_str(" ON DUPLICATE KEY UPDATE ");
+                let updates: Vec<String> = columns.iter().map(|c| format!("`{}` = VALUES(`{}`)", c, c)).collect();
+                sql.push_str(&updates.join(", "));
+            }
+            statements.push(sql);
+        }
+
+        let mut attempt = 0;
+        loop {
+            if self.commit_statements(db, &statements) {
+                break;
+            }
+            attempt += 1;
+            if attempt > self.config.max_retries {
+                fail!((redis::ErrorKind::IoError, "write behind flush failed", db.get_error_str().unwrap_or_default()));
+            }
+            thread::sleep(self.config.retry_delay * 2u32.pow(attempt - 1));
+        }
+
+        let mut invocation = ScriptRegistry::instance().invocation(COMMIT_SCRIPT_NAME);
+        invocation.key(&*dirty_key);
+        for (member, score) in &flushed {
+            invocation.arg(member.as_str()).arg(score.as_str());
+        }
+        let removed: i64 = invocation.invoke(conn)?;
+        Ok(removed as usize)
+    }
+
+    fn commit_statements(&self, db: &mut DbMysql, statements: &[String]) -> bool {
+        if ok_or!(db.begin_transaction(), return false) != 0 {
+            return false;
+        }
+        for sql in statements {
+            if ok_or!(db.execute(sql), -1) != 0 {
+                let _ = db.rollback_transaction();
+                return false;
+            }
+        }
+        ok_or!(db.commit_transaction(), return false) == 0
+    }
+
+    /// start `count` worker threads cycling over every shard until `stop` is called.
+    /// `connect` opens the mysql connection of a worker
+    pub fn start_workers<F>(self: Arc<Self>, count: usize, connect: F) -> Vec<JoinHandle<()>>
+        where F: Fn() -> Option<DbMysql> + Send + Sync + 'static
+    {
+        let connect = Arc::new(connect);
+        let mut handles = vec![];
+        for worker in 0..count {
+            let this = self.clone();
+            let connect = connect.clone();
+            handles.push(thread::spawn(move || {
+                let mut db = None;
+                let mut shard = worker as u16 % ::std::cmp::max(this.config.shards, 1);
+                while !this.stop.load(Ordering::SeqCst) {
+                    if db.is_none() {
+                        db = (*connect)();
+                    }
+                    let mut flushed = 0;
+                    if let Some(ref mut db) = db {
+                        let pool = RedisPool::instance();
+                        if let Some(mut conn) = pool.get_redis_connection() {
+                            flushed = this.flush_shard(&mut conn, db, shard).unwrap_or(0);
+                            pool.release_redis_connection(conn);
+                        }
+                    }
+                    shard = (shard + 1) % ::std::cmp::max(this.config.shards, 1);
+                    if flushed == 0 {
+                        thread::sleep(this.config.flush_interval);
+                    }
+                }
+            }));
+        }
+        handles
+    }
+
+    pub fn stop(&self) {
+        self.stop.store(true, Ordering::SeqCst);
+    }
+}
@@ -6,5 +6,8 @@ mod db_pool;
 pub mod db_sqlite;
 pub mod db_redis;
 pub mod db_mysql;
+pub mod db_cache;
+pub mod db_write_behind;
+pub mod db_leaderboard;
 
-mod db_trait;
\ No newline at end of file
+pub mod db_trait;
\ No newline at end of file
@@ -0,0 +1,67 @@
+//! needs the server of `scripts/mysql_tls.sh start`, run with
+//! `cargo test --test mysql_tls -- --ignored`. `MYSQL_TLS_DIR` and `MYSQL_TLS_PORT`
+//! must match the values the script was started with
+
+use mysql::prelude::Queryable;
+use rua_db_trait::db_mysql::{DbMysql, MysqlOptions};
+
+static PASS: &'static str = "rua_pass";
+
+fn cert_dir() -> String {
+    std::env::var("MYSQL_TLS_DIR").unwrap_or_else(|_| "/tmp/rua_mysql_tls".to_string())
+}
+
+fn port() -> String {
+    std::env::var("MYSQL_TLS_PORT").unwrap_or_else(|_| "3307".to_string())
+}
+
+fn connect(user: &str, host: &str, params: &str) -> Option<DbMysql> {
+    let url = format!("mysql://{}:{}@{}:{}/rua_test?{}", user, PASS, host, port(), params);
+    let options = MysqlOptions::from_url(&url).unwrap();
+    DbMysql::from_options(&options).ok()
+}
+
+fn cipher(db: &mut DbMysql) -> String {
+    let row: Option<(String, String)> = db.conn.query_first("SHOW SESSION STATUS LIKE 'Ssl_cipher'").unwrap();
+    row.map(|(_, cipher)| cipher).unwrap_or_default()
+}
+
+#[test]
+#[ignore]
+fn verified_ca() {
+    let mut db = connect("root", "localhost", &format!("ssl_ca={}/ca.pem", cert_dir())).expect("verified ca connection");
+    assert!(!cipher(&mut db).is_empty());
+}
+
+#[test]
+#[ignore]
+fn client_identity() {
+    let params = format!("ssl_ca={0}/ca.pem&ssl_identity={0}/client.p12&ssl_identity_password={1}", cert_dir(), PASS);
+    let mut db = connect("rua_x509", "localhost", &params).expect("client identity connection");
+    assert!(!cipher(&mut db).is_empty());
+}
+
+#[test]
+#[ignore]
+fn x509_user_needs_identity() {
+    assert!(connect("rua_x509", "localhost", &format!("ssl_ca={}/ca.pem", cert_dir())).is_none());
+}
+
+#[test]
+#[ignore]
+fn wrong_hostname_fails() {
+    assert!(connect("root", "127.0.0.2", &format!("ssl_ca={}/ca.pem", cert_dir())).is_none());
+}
+
+#[test]
+#[ignore]
+fn skip_verify() {
+    let mut db = connect("root", "127.0.0.2", "ssl_skip_verify=true").expect("skip verify connection");
+    assert!(!cipher(&mut db).is_empty());
+}
+
+#[test]
+#[ignore]
+fn plain_fails() {
+    assert!(connect("root", "localhost", "ssl=false").is_none());
+}
@@ -0,0 +1,36 @@
+//! needs the local cluster of `scripts/redis_cluster.sh start`, run with
+//! `cargo test --test redis_cluster -- --ignored`. `RUA_REDIS_CLUSTER` overrides the
+//! comma separated node urls
+
+use rua_db_trait::db_redis::RedisPool;
+
+fn cluster_urls() -> Vec<String> {
+    match std::env::var("RUA_REDIS_CLUSTER") {
+        Ok(urls) => urls.split(',').map(|url| url.trim().to_string()).collect(),
+        Err(_) => (7000..7003).map(|port| format!("redis://127.0.0.1:{}/", port)).collect(),
+    }
+}
+
+#[test]
+#[ignore]
+fn mget_keeps_key_order() {
+    let pool = RedisPool::instance();
+    pool.set_url_list(cluster_urls());
+    let mut conn = pool.get_redis_connection().expect("redis cluster connection");
+    assert!(conn.is_cluster());
+
+    // spread over several slots, interleaved with a missing key
+    let keys = ["rua_test:a", "rua_test:b", "rua_test:missing", "{rua_test}:c", "rua_test:d", "rua_test:e"];
+    let _ = conn.del_keys(&keys);
+    let pairs: Vec<(&str, i64)> = keys.iter().enumerate()
+        .filter(|(_, key)| !key.ends_with("missing"))
+        .map(|(i, key)| (*key, i as i64 * 10))
+        .collect();
+    conn.mset_values(&pairs).unwrap();
+
+    let values: Vec<Option<i64>> = conn.mget_values(&keys).unwrap();
+    assert_eq!(values, vec![Some(0), Some(10), None, Some(30), Some(40), Some(50)]);
+    assert_eq!(conn.exists_keys(&keys).unwrap(), 5);
+    assert_eq!(conn.del_keys(&keys).unwrap(), 5);
+    pool.release_redis_connection(conn);
+}